@@ -0,0 +1,182 @@
+use crate::BoxedErrorResult;
+use crate::component_manager::*;
+use crate::globals;
+use crate::heartbeat::{self, Incarnation, LeaveOperation, NewMemberOperation};
+use crate::operation::*;
+use rand::seq::SliceRandom;
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::convert::TryInto;
+
+// Push-pull anti-entropy for the membership list. Joins and leaves are still broadcast right away, but every
+// gossip_interval each node also swaps its versioned view of the membership with a random member, so a dropped
+// packet only delays convergence instead of splitting the views for good. Only the version of every record is
+// sent at first, and each side then pushes the full records the other one is missing or behind on.
+
+// The version of a member's record is its incarnation, with leaving winning over being alive at the same one
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MemberRecord {
+    pub incarnation: Incarnation,
+    pub left: bool,
+    pub tcp_addr: Option<String>,
+    pub weight: u32
+}
+
+pub type RecordVersion = (Incarnation, bool);
+
+impl MemberRecord {
+    fn version(&self) -> RecordVersion {
+        (self.incarnation, self.left)
+    }
+}
+
+pub fn gossiper(sender: &OperationSender) -> ComponentResult {
+    if !is_joined() {
+        return Ok(())
+    }
+    heartbeat::forget_departed_members()?;
+    let my_id = globals::MY_ID.read().clone();
+    let peers: Vec<String> = globals::MEMBERSHIP_LIST.read()
+        .iter()
+        .filter(|x| **x != my_id)
+        .map(heartbeat::ip_from_id)
+        .collect();
+    // A node whose join got lost only knows about itself, so it keeps knocking on the introducers instead
+    let peer = match peers.choose(&mut rand::thread_rng()) {
+        Some(peer) => peer.clone(),
+        None => {
+            let my_addr = globals::MY_IP_ADDR.read().clone();
+            let introducers: Vec<String> = globals::CONFIG.read().introducers
+                .iter()
+                .filter(|x| **x != my_addr)
+                .cloned()
+                .collect();
+            match introducers.choose(&mut rand::thread_rng()) {
+                Some(introducer) => introducer.clone(),
+                None => return Ok(())
+            }
+        }
+    };
+    sender.send(SendableOperation {
+        dests: Destinations::UDPAddr(vec![peer]),
+        operation: Box::new(MembershipDigestOperation {
+            versions: record_versions(&local_records()),
+            records: HashMap::new(),
+            reply: true
+        })
+    })?;
+    Ok(())
+}
+
+// Members that left are remembered through their last incarnation, so they can't be brought back by a stale view
+pub fn local_records() -> HashMap<String, MemberRecord> {
+    let membership_list = globals::MEMBERSHIP_LIST.read().clone();
+    let incarnations = globals::INCARNATIONS.read().clone();
+    let udp_to_tcp_map = globals::UDP_TO_TCP_MAP.read().clone();
+    let member_weights = globals::MEMBER_WEIGHTS.read().clone();
+    incarnations.into_iter().map(|(id, incarnation)| {
+        let left = membership_list.binary_search(&id).is_err();
+        let tcp_addr = match left {
+            true => None,
+            false => udp_to_tcp_map.get(&heartbeat::ip_from_id(&id)).cloned()
+        };
+        let weight = member_weights.get(&id).cloned().unwrap_or(1);
+        (id, MemberRecord {
+            incarnation,
+            left,
+            tcp_addr,
+            weight
+        })
+    }).collect()
+}
+
+fn record_versions(records: &HashMap<String, MemberRecord>) -> HashMap<String, RecordVersion> {
+    records.iter().map(|(id, record)| (id.clone(), record.version())).collect()
+}
+
+fn merge_records(records: &HashMap<String, MemberRecord>) -> BoxedErrorResult<Vec<SendableOperation>> {
+    let mut generated_operations: Vec<SendableOperation> = Vec::new();
+    let my_id = globals::MY_ID.read().clone();
+    for (id, record) in records.iter() {
+        if *id == my_id {
+            if record.left && record.incarnation >= heartbeat::my_incarnation() {
+                generated_operations.append(&mut heartbeat::refute(record.incarnation)?);
+            }
+            continue;
+        }
+        let is_newer = heartbeat::incarnation_of(id).is_none_or(|known| record.incarnation > known);
+        if record.left && heartbeat::incarnation_of(id).is_none() {
+            // Nothing to remove, and we may well have forgotten it on purpose already
+            continue;
+        } else if record.left {
+            // Stale leaves are ignored by the operation itself
+            generated_operations.append(&mut LeaveOperation {
+                id: id.clone(),
+                incarnation: record.incarnation
+            }.execute(Source::myself())?);
+        } else if let (true, Some(tcp_addr)) = (is_newer, &record.tcp_addr) {
+            log(format!("Anti-entropy found member {} at incarnation {}", id, record.incarnation))?;
+            generated_operations.append(&mut NewMemberOperation {
+                id: id.clone(),
+                tcp_addr: tcp_addr.clone(),
+                incarnation: record.incarnation,
+                weight: record.weight
+            }.execute(Source::myself())?);
+        }
+    }
+    Ok(generated_operations)
+}
+
+fn newer_records(ours: &HashMap<String, MemberRecord>, theirs: &HashMap<String, RecordVersion>) -> HashMap<String, MemberRecord> {
+    ours.iter()
+        .filter(|(id, record)| theirs.get(*id).is_none_or(|their_version| record.version() > *their_version))
+        .map(|(id, record)| (id.clone(), record.clone()))
+        .collect()
+}
+
+// Operations
+// A round is three of these: the versions of the initiator's records, then the records it is behind on along
+// with the versions of the peer's, then the records the peer is behind on
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MembershipDigestOperation {
+    // Empty when the sender doesn't want anything pushed back
+    pub versions: HashMap<String, RecordVersion>,
+    pub records: HashMap<String, MemberRecord>,
+    // Whether the receiver should send its own versions along
+    pub reply: bool
+}
+
+// Trait Impls
+impl OperationWriteExecute for MembershipDigestOperation {
+    fn to_bytes(&self) -> BoxedErrorResult<Vec<u8>> {
+        Ok(create_buf(&self, str_to_vec(Self::TAG)))
+    }
+    fn execute(&self, source: Source) -> BoxedErrorResult<Vec<SendableOperation>> {
+        if !is_joined() {
+            return Ok(vec![])
+        }
+        let mut generated_operations = merge_records(&self.records)?;
+        if self.versions.is_empty() && !self.reply {
+            return Ok(generated_operations)
+        }
+        // Push whatever the other side is missing or has an older version of, after taking in what it pushed
+        let local_records = local_records();
+        let newer = newer_records(&local_records, &self.versions);
+        if !newer.is_empty() || self.reply {
+            let source_addr: String = source.try_into()?;
+            generated_operations.push(SendableOperation {
+                dests: Destinations::UDPAddr(vec![source_addr]),
+                operation: Box::new(MembershipDigestOperation {
+                    versions: match self.reply {
+                        true  => record_versions(&local_records),
+                        false => HashMap::new()
+                    },
+                    records: newer,
+                    reply: false
+                })
+            });
+        }
+        Ok(generated_operations)
+    }
+    fn to_string(&self) -> String { format!("{:?}", self) }
+}
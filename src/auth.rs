@@ -0,0 +1,123 @@
+use crate::BoxedErrorResult;
+use crate::config::Config;
+use crate::constants::{AUTH_TRAILER_SIZE, HEADER_SIZE, MAC_SIZE, MIN_UDP_SECRET_SIZE};
+use crate::globals;
+use crate::heartbeat::{self, Timestamp};
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::convert::TryInto;
+
+// Authentication for the UDP channel. With a shared secret configured, every datagram carries a trailer after the
+// frame (see constants.rs) with the time it was sent, a random nonce and an HMAC over all of it. A receiver drops
+// datagrams with a bad MAC, stamped outside its replay window, or reusing a nonce seen within that window, so
+// control messages can be neither forged nor replayed. Without a secret, datagrams go out bare.
+
+type HmacSha256 = Hmac<Sha256>;
+
+// The nonces accepted within the replay window, oldest stamp first so that forgetting the expired ones only looks
+// at those rather than at every nonce on every datagram
+#[derive(Debug, Default)]
+pub struct SeenNonces {
+    pub stamps: HashMap<u64, Timestamp>,
+    pub by_stamp: BinaryHeap<Reverse<(Timestamp, u64)>>
+}
+
+impl SeenNonces {
+    // Anything stamped before the window is turned away anyway, so its nonce no longer needs remembering
+    fn forget_before(&mut self, oldest: Timestamp) {
+        while let Some(Reverse((timestamp, nonce))) = self.by_stamp.peek().cloned() {
+            if timestamp >= oldest {
+                break;
+            }
+            self.by_stamp.pop();
+            if self.stamps.get(&nonce) == Some(&timestamp) {
+                self.stamps.remove(&nonce);
+            }
+        }
+    }
+    // False if the nonce was already seen
+    fn insert(&mut self, nonce: u64, timestamp: Timestamp) -> bool {
+        if self.stamps.contains_key(&nonce) {
+            return false;
+        }
+        self.stamps.insert(nonce, timestamp);
+        self.by_stamp.push(Reverse((timestamp, nonce)));
+        true
+    }
+}
+
+pub fn load(config: &Config) -> BoxedErrorResult<Option<Vec<u8>>> {
+    let path = match &config.udp_secret_file {
+        Some(path) => path,
+        None => return Ok(None)
+    };
+    let secret = std::fs::read_to_string(path).map_err(|e| format!("Could not read the UDP secret from {}: {}", path, e))?;
+    let secret = secret.trim();
+    if secret.len() < MIN_UDP_SECRET_SIZE {
+        return Err(format!("The UDP secret in {} must be at least {} bytes", path, MIN_UDP_SECRET_SIZE).into())
+    }
+    Ok(Some(secret.as_bytes().to_vec()))
+}
+
+// How big a frame gets once sealed, to know ahead of time whether it still fits in a datagram
+pub fn sealed_size(frame_size: usize) -> usize {
+    match &*globals::UDP_SECRET.read() {
+        Some(_) => frame_size + AUTH_TRAILER_SIZE,
+        None => frame_size
+    }
+}
+
+pub fn seal(frame: &mut Vec<u8>) -> BoxedErrorResult<()> {
+    match &*globals::UDP_SECRET.read() {
+        Some(secret) => seal_with(frame, secret, heartbeat::get_timestamp()?),
+        None => Ok(())
+    }
+}
+
+// Checks the trailer and strips it off, leaving just the frame
+pub fn open(datagram: &mut Vec<u8>) -> BoxedErrorResult<()> {
+    let secret = match &*globals::UDP_SECRET.read() {
+        Some(secret) => secret.clone(),
+        None => return Ok(())
+    };
+    let replay_window = globals::CONFIG.read().udp_replay_window;
+    open_with(datagram, &secret, heartbeat::get_timestamp()?, replay_window, &mut globals::SEEN_NONCES.get_mut())
+}
+
+pub fn seal_with(frame: &mut Vec<u8>, secret: &[u8], timestamp: Timestamp) -> BoxedErrorResult<()> {
+    frame.extend_from_slice(&timestamp.to_le_bytes());
+    frame.extend_from_slice(&rand::random::<u64>().to_le_bytes());
+    let mac = new_mac(secret, frame)?.finalize().into_bytes();
+    frame.extend_from_slice(&mac);
+    Ok(())
+}
+
+pub fn open_with(datagram: &mut Vec<u8>, secret: &[u8], now: Timestamp, replay_window: u64,
+                 seen_nonces: &mut SeenNonces) -> BoxedErrorResult<()> {
+    if datagram.len() < HEADER_SIZE + AUTH_TRAILER_SIZE {
+        return Err(format!("{} bytes is too short to be authenticated", datagram.len()).into())
+    }
+    let mac_start = datagram.len() - MAC_SIZE;
+    new_mac(secret, &datagram[..mac_start])?.verify(&datagram[mac_start..])
+        .map_err(|_| "Bad MAC")?;
+    let timestamp = Timestamp::from_le_bytes(datagram[mac_start - 16..mac_start - 8].try_into()?);
+    let nonce = u64::from_le_bytes(datagram[mac_start - 8..mac_start].try_into()?);
+    let skew = now.abs_diff(timestamp);
+    if skew > replay_window {
+        return Err(format!("Stamped {} ms away from now, outside the replay window of {} ms", skew, replay_window).into())
+    }
+    seen_nonces.forget_before(now.saturating_sub(replay_window));
+    if !seen_nonces.insert(nonce, timestamp) {
+        return Err(format!("Replayed nonce {}", nonce).into())
+    }
+    datagram.truncate(datagram.len() - AUTH_TRAILER_SIZE);
+    Ok(())
+}
+
+fn new_mac(secret: &[u8], data: &[u8]) -> BoxedErrorResult<HmacSha256> {
+    let mut mac = HmacSha256::new_from_slice(secret).map_err(|e| format!("Invalid UDP secret: {:?}", e))?;
+    mac.update(data);
+    Ok(mac)
+}
@@ -1,15 +1,20 @@
 use crate::BoxedErrorResult;
+use crate::config::{self, Config};
 use crate::constants;
+use crate::anti_entropy;
+use crate::auth;
+use crate::failure_detector;
 use crate::filesystem;
 use crate::globals;
+use crate::hash_ring::HashRing;
 use crate::heartbeat;
 use crate::operation::*;
-use std::collections::HashMap;
+use crate::tls;
+use std::collections::{HashMap, VecDeque};
 use std::future::Future;
 use std::fs::{self, File, OpenOptions};
 use std::io::{self, Write};
-use std::net::{Ipv4Addr, UdpSocket};
-use std::str::FromStr;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
 use std::sync::{mpsc};
 use std::{thread, time};
 
@@ -34,16 +39,34 @@ pub fn start_receiver(freq_interval: FrequencyInterval, sender: OperationSender)
 
 pub fn start_maintainer(freq_interval: FrequencyInterval, sender: OperationSender) {
     thread::spawn(move || {
-        start_component(&mut heartbeat::maintainer, &sender, freq_interval);
+        start_component(&mut maintainer, &sender, freq_interval);
     });    
 }
 
+pub fn start_failure_detector(freq_interval: FrequencyInterval, sender: OperationSender) {
+    thread::spawn(move || {
+        start_component(&mut failure_detector::detector, &sender, freq_interval);
+    });
+}
+
 pub fn start_file_server(freq_interval: FrequencyInterval, sender: OperationSender) {
     thread::spawn(move || {
         start_async_component(&mut filesystem::file_server, &sender, freq_interval);
     });    
 }
 
+pub fn start_rebalancer(freq_interval: FrequencyInterval, sender: OperationSender) {
+    thread::spawn(move || {
+        start_component(&mut filesystem::rebalancer, &sender, freq_interval);
+    });
+}
+
+pub fn start_gossiper(freq_interval: FrequencyInterval, sender: OperationSender) {
+    thread::spawn(move || {
+        start_component(&mut anti_entropy::gossiper, &sender, freq_interval);
+    });
+}
+
 pub fn start_console(freq_interval: FrequencyInterval, sender: OperationSender) {
     thread::spawn(move || {
         start_component(&mut console, &sender, freq_interval);
@@ -52,35 +75,56 @@ pub fn start_console(freq_interval: FrequencyInterval, sender: OperationSender)
 }
 
 // Utility Functions
-pub async fn startup(udp_port: u16) -> BoxedErrorResult<()> {
-    startup_log_file(udp_port);
-    startup_data_dir();
-    let (udp_addr, tcp_addr) = get_socket_addrs(udp_port, udp_port+3)?;
-    // TODO: Have a better scheme for TCP port
-    globals::UDP_SOCKET.write(UdpSocket::bind(&udp_addr)?);
+pub async fn startup(config: Config) -> BoxedErrorResult<()> {
+    let addrs = get_socket_addrs(&config)?;
+    // Fail on bad certificates or secrets before binding anything
+    globals::TLS.write(tls::load(&config)?);
+    globals::UDP_SECRET.write(auth::load(&config)?);
+    globals::CONFIG.write(config);
+    startup_log_file(&addrs.udp_bind)?;
+    startup_data_dir()?;
+    let udp_socket = UdpSocket::bind(addrs.udp_bind)
+        .map_err(|e| format!("Could not bind UDP socket to {}: {}", addrs.udp_bind, e))?;
+    let tcp_listener = async_std::net::TcpListener::bind(addrs.tcp_bind).await
+        .map_err(|e| format!("Could not bind TCP listener to {}: {}", addrs.tcp_bind, e))?;
+    globals::UDP_SOCKET.write(udp_socket);
     globals::IS_JOINED.write(false);
     globals::MEMBERSHIP_LIST.write(Vec::new());
     globals::SUCCESSOR_LIST.write(Vec::new());
-    globals::PREDECESSOR_LIST.write(Vec::new());
-    globals::PREDECESSOR_TIMESTAMPS.write(HashMap::new());
-    globals::MY_IP_ADDR.write(udp_addr.to_string());
+    globals::INCARNATIONS.write(HashMap::new());
+    globals::DEPARTED_AT.write(HashMap::new());
+    globals::MEMBER_WEIGHTS.write(HashMap::new());
+    globals::HASH_RING.write(HashRing::new(&Vec::new(), &HashMap::new(), 0, globals::CONFIG.read().hash_algorithm));
+    failure_detector::reset();
+    globals::MY_IP_ADDR.write(addrs.udp_advertised.to_string());
     globals::DEBUG.write(true);
-    globals::TCP_ADDR.write(tcp_addr.clone());
-    globals::SERVER_SOCKET.write(async_std::net::TcpListener::bind(tcp_addr).await?);
+    globals::TCP_ADDR.write(addrs.tcp_advertised.to_string());
+    globals::SERVER_SOCKET.write(tcp_listener);
     globals::UDP_TO_TCP_MAP.write(HashMap::new());
     globals::ALL_FILE_OWNERS.write(HashMap::new());
+    globals::FILE_TOMBSTONES.write(HashMap::new());
+    globals::TOMBSTONE_STATES.write(HashMap::new());
+    globals::RESERVED_VERSIONS.write(HashMap::new());
+    globals::REBALANCE_NEEDED.write(false);
+    globals::REBALANCE_QUEUE.write(VecDeque::new());
+    globals::NEXT_REQUEST_ID.write(0);
+    globals::PROTOCOL_VERSION.write(constants::PROTOCOL_VERSION);
+    globals::SEEN_NONCES.write(auth::SeenNonces::default());
+    filesystem::load_node_state()?;
     Ok(())
 }
 
 fn startup_data_dir() -> BoxedErrorResult<()> {
-    if let Err(_) = fs::create_dir(constants::DATA_DIR) {}
+    let _ = fs::create_dir_all(&globals::CONFIG.read().data_dir);
     Ok(())
 }
 
-fn startup_log_file(port: u16) -> BoxedErrorResult<()> {
-    if let Err(_) = fs::create_dir(constants::LOG_DIR) {}
+fn startup_log_file(udp_addr: &SocketAddr) -> BoxedErrorResult<()> {
+    let log_dir = globals::CONFIG.read().log_dir.clone();
+    let _ = fs::create_dir_all(&log_dir);
+    let port = udp_addr.port();
     let timestamp = heartbeat::get_timestamp()?;
-    let debug_file = format!("{}/port_{}_{:020}.txt", constants::LOG_DIR, port, timestamp);
+    let debug_file = format!("{}/port_{}_{:020}.txt", log_dir, port, timestamp);
     globals::LOG_FILE.write(OpenOptions::new()
                               .read(true)
                               .write(true)
@@ -132,33 +176,50 @@ where F: Future<Output = BoxedErrorResult<T>> {
 
 // Components
 pub fn sender(receiver: &OperationReceiver) -> ComponentResult {
-    // Empty the queue by sending all outstanding operations
-    // Do this before heartbeating so that we can empty after a leave
+    // Send operations as soon as they are queued since acks for probes are time sensitive
+    let queue_item = receiver.recv()?;
     let udp_socket = globals::UDP_SOCKET.read();
+    queue_item.write_all_udp(&udp_socket)?;
     while let Ok(queue_item) = receiver.try_recv() {
         queue_item.write_all_udp(&udp_socket)?;
     }
-
-    // Send heartbeat packets    
-    if is_joined() {
-        heartbeat::send_heartbeats();
-    }
     Ok(())
 }
 
+pub fn maintainer(_sender: &OperationSender) -> ComponentResult {
+    filesystem::persist_node_state()
+}
+
 pub fn receiver(sender: &OperationSender) -> ComponentResult {
     let udp_socket = globals::UDP_SOCKET.read();
     loop {
         if is_joined() {
             // let (operation, source) = read_operation(&*udp_socket)?;
-            let (operation, source) = udp_socket.try_read_operation()?;
-            let generated_operations = operation.execute(source)?;
+            // One bad frame shouldn't hold up the ones queued behind it
+            let (operation, source) = match udp_socket.try_read_operation() {
+                Ok(read) => read,
+                Err(e) => {
+                    log(e.to_string())?;
+                    continue;
+                }
+            };
+            // Neither should one that fails, or a stream of them would keep the receiver from answering probes
+            let generated_operations = match operation.execute(source) {
+                Ok(generated_operations) => generated_operations,
+                Err(e) => {
+                    log(format!("Failed to execute a {}: {}", operation.to_string(), e))?;
+                    continue;
+                }
+            };
             for generated_operation in generated_operations {
                 sender.send(generated_operation)?;
             }
         } else {
-            // Drop the packet
-            udp_socket.recv_from(&mut vec![0])?;
+            // Drop the packet - it is only peeked at first, since a join while waiting makes it ours to read
+            udp_socket.peek_from(&mut [0])?;
+            if !is_joined() {
+                udp_socket.recv_from(&mut [0])?;
+            }
         }
     }
 }
@@ -176,9 +237,11 @@ pub fn console(sender: &OperationSender) -> ComponentResult {
         "leave" => heartbeat::leave(args, sender)?,
         "print" => heartbeat::print(args)?,
         "get"   => filesystem::get(args)?,
+        "get-versions" => filesystem::get_versions(args)?,
         "put"   => filesystem::put(args, sender)?,
+        "delete" => filesystem::delete(args, sender)?,
         "ls"    => filesystem::ls(args)?,
-        "is_master" => println!("{}", heartbeat::is_master()),
+        "ls-node" => filesystem::ls_node(args)?,
         _       => println!("Invalid command. (Maybe replace with a help func)")
     }
     Ok(())
@@ -203,27 +266,62 @@ fn parse_frequency(freq_interval: FrequencyInterval) -> u64 {
     }
 }
 
-// TODO: Eventually, change this to external IPs
-fn get_socket_addrs(udp_port: u16, tcp_port: u16) -> BoxedErrorResult<(String, String)> {
-    let local_addr = get_local_addr()?;
-    let udp_addr = format!("{}:{}", local_addr, udp_port);
-    let tcp_addr = format!("{}:{}", local_addr, tcp_port);
-    Ok((udp_addr, tcp_addr))
+struct SocketAddrs {
+    udp_bind: SocketAddr,
+    tcp_bind: SocketAddr,
+    udp_advertised: SocketAddr,
+    tcp_advertised: SocketAddr
 }
 
-fn get_local_addr() -> BoxedErrorResult<String> {
-    for iface in get_if_addrs::get_if_addrs().unwrap() {
+// Explicit addresses win, then ports on the detected local address, and TCP falls back to the UDP port + 3
+// The advertised addresses default to the bound ones, so they are only needed behind NAT or in containers
+fn get_socket_addrs(config: &Config) -> BoxedErrorResult<SocketAddrs> {
+    let udp_bind = match (&config.udp_addr, config.port) {
+        (Some(udp_addr), _) => config::parse_addr("udp_addr", udp_addr)?,
+        (None, Some(port)) => SocketAddr::new(get_local_addr()?, port),
+        (None, None) => return Err("No UDP address or port configured".into())
+    };
+    let tcp_bind = match (&config.tcp_addr, config.tcp_port) {
+        (Some(tcp_addr), _) => config::parse_addr("tcp_addr", tcp_addr)?,
+        (None, Some(port)) => SocketAddr::new(udp_bind.ip(), port),
+        (None, None) => SocketAddr::new(udp_bind.ip(), udp_bind.port().checked_add(3)
+                                        .ok_or("UDP port is too large to derive a TCP port from, set tcp_port")?)
+    };
+    let udp_advertised = match &config.advertise_udp_addr {
+        Some(addr) => config::parse_addr("advertise_udp_addr", addr)?,
+        None => udp_bind
+    };
+    let tcp_advertised = match &config.advertise_tcp_addr {
+        Some(addr) => config::parse_addr("advertise_tcp_addr", addr)?,
+        None => tcp_bind
+    };
+    for (name, addr) in &[("advertise_udp_addr", udp_advertised), ("advertise_tcp_addr", tcp_advertised)] {
+        if addr.ip().is_unspecified() {
+            return Err(format!("Cannot advertise the wildcard address {}, set {}", addr, name).into())
+        }
+    }
+    Ok(SocketAddrs { udp_bind, tcp_bind, udp_advertised, tcp_advertised })
+}
+
+// Prefers a non-loopback IPv4 address, but loopback still works for running several nodes on one host
+fn get_local_addr() -> BoxedErrorResult<IpAddr> {
+    let ifaces = get_if_addrs::get_if_addrs()
+        .map_err(|e| format!("Could not list network interfaces: {}", e))?;
+    let mut loopback = None;
+    for iface in ifaces {
         if let get_if_addrs::IfAddr::V4(v4_addr) = iface.addr {
-            if v4_addr.netmask == Ipv4Addr::from_str("255.255.255.0").unwrap() {
-                return Ok(v4_addr.ip.to_string());
+            if !v4_addr.ip.is_loopback() {
+                return Ok(IpAddr::V4(v4_addr.ip));
             }
+            loopback = Some(IpAddr::V4(v4_addr.ip));
         }
     }
-    Err("Could not find valid local IPv4 address".to_string().into())
+    loopback.ok_or("Could not find a local IPv4 address, set udp_addr explicitly".into())
 }
 
 // TODO: Maybe find another place for this - Also: borrow or owned?
 pub fn log(msg: String) -> BoxedErrorResult<()> {
-    writeln!(*globals::LOG_FILE.get_mut(), "{}", msg)?;
+    let timestamp = heartbeat::get_timestamp()?;
+    writeln!(*globals::LOG_FILE.get_mut(), "{} {}", timestamp, msg)?;
     Ok(())
 }
@@ -0,0 +1,245 @@
+use crate::BoxedErrorResult;
+use crate::constants;
+use crate::easyhash::HashAlgorithm;
+use serde::Deserialize;
+use std::net::SocketAddr;
+
+// Everything that can change between deployments without a recompile
+// Precedence is defaults (constants.rs) < config file < command line flags
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub introducers: Vec<String>,
+    // Bind addresses - a bare port is paired with the detected local address
+    pub port: Option<u16>,
+    pub tcp_port: Option<u16>,
+    pub udp_addr: Option<String>,
+    pub tcp_addr: Option<String>,
+    // What other members are told to use, for when the bind address is not reachable (NAT, containers)
+    pub advertise_udp_addr: Option<String>,
+    pub advertise_tcp_addr: Option<String>,
+    pub log_dir: String,
+    pub data_dir: String,
+    pub num_owners: u32,
+    pub num_successors: u32,
+    pub write_quorum: Option<u32>,
+    pub read_quorum: Option<u32>,
+    // Failure detection - one member is probed every probe_interval, through indirect_probes others if it
+    // doesn't ack within ping_timeout, and it is declared dead after being suspected for suspect_timeout
+    pub probe_interval: u64,
+    pub ping_timeout: u64,
+    pub suspect_timeout: u64,
+    pub indirect_probes: u32,
+    pub quorum_timeout: u64,
+    // Remote calls give up on an attempt after rpc_timeout ms and try again up to rpc_retries more times
+    pub rpc_timeout: u64,
+    pub rpc_retries: u32,
+    pub chunk_size: usize,
+    // Largest frame accepted over TCP, so a bogus size in a header can't make us allocate whatever it says
+    pub max_frame_size: usize,
+    // Hashes file names and members onto the ring - must match across the cluster
+    pub hash_algorithm: HashAlgorithm,
+    // Points each member gets on the hash ring per unit of weight - must match across the cluster
+    pub virtual_nodes: u32,
+    // This node's share of the files relative to the others, e.g. 2 for twice the disk of a weight 1 node
+    pub weight: u32,
+    // Every gossip_interval ms the membership view is reconciled with a random member
+    pub gossip_interval: u64,
+    // Members that left are remembered for departed_member_ttl ms so that stale views can't bring them back
+    pub departed_member_ttl: u64,
+    // Deleted files are remembered for file_tombstone_ttl ms after every owner confirmed dropping its replicas
+    pub file_tombstone_ttl: u64,
+    // Rebalancing moves at most rebalance_batch_size files every rebalance_interval ms
+    pub rebalance_interval: u64,
+    pub rebalance_batch_size: usize,
+    // PEM files for mutual TLS on the TCP channel - the cluster CA, and this node's certificate (issued for its
+    // TCP IP) and key. All three or none, in which case the channel is plain TCP.
+    pub tls_ca_file: Option<String>,
+    pub tls_cert_file: Option<String>,
+    pub tls_key_file: Option<String>,
+    // Shared secret every datagram is authenticated with, the same on every node. Datagrams stamped more than
+    // udp_replay_window ms off from this node's clock are dropped, so clocks have to be roughly in sync.
+    pub udp_secret_file: Option<String>,
+    pub udp_replay_window: u64
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            introducers: constants::IP_LIST.iter().map(|x| x.to_string()).collect(),
+            port: None,
+            tcp_port: None,
+            udp_addr: None,
+            tcp_addr: None,
+            advertise_udp_addr: None,
+            advertise_tcp_addr: None,
+            log_dir: constants::LOG_DIR.to_string(),
+            data_dir: constants::DATA_DIR.to_string(),
+            num_owners: constants::NUM_OWNERS,
+            num_successors: constants::NUM_SUCCESSORS,
+            write_quorum: constants::WRITE_QUORUM,
+            read_quorum: constants::READ_QUORUM,
+            probe_interval: constants::PROBE_INTERVAL,
+            ping_timeout: constants::PING_TIMEOUT,
+            suspect_timeout: constants::SUSPECT_TIMEOUT,
+            indirect_probes: constants::INDIRECT_PROBES,
+            quorum_timeout: constants::QUORUM_TIMEOUT,
+            rpc_timeout: constants::RPC_TIMEOUT,
+            rpc_retries: constants::RPC_RETRIES,
+            chunk_size: constants::CHUNK_SIZE,
+            max_frame_size: constants::MAX_FRAME_SIZE,
+            hash_algorithm: constants::HASH_ALGORITHM,
+            virtual_nodes: constants::VIRTUAL_NODES,
+            weight: constants::WEIGHT,
+            gossip_interval: constants::GOSSIP_INTERVAL,
+            departed_member_ttl: constants::DEPARTED_MEMBER_TTL,
+            file_tombstone_ttl: constants::FILE_TOMBSTONE_TTL,
+            rebalance_interval: constants::REBALANCE_INTERVAL,
+            rebalance_batch_size: constants::REBALANCE_BATCH_SIZE,
+            tls_ca_file: None,
+            tls_cert_file: None,
+            tls_key_file: None,
+            udp_secret_file: None,
+            udp_replay_window: constants::UDP_REPLAY_WINDOW
+        }
+    }
+}
+
+impl Config {
+    pub fn from_file(path: &str) -> BoxedErrorResult<Self> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Could not read config file {}: {}", path, e))?;
+        let config = toml::from_str(&contents)
+            .map_err(|e| format!("Could not parse config file {}: {}", path, e))?;
+        Ok(config)
+    }
+
+    pub fn apply_flag(&mut self, flag: &str, value: &str) -> BoxedErrorResult<()> {
+        match flag {
+            "--introducers"   => self.introducers = value.split(',').map(|x| x.trim().to_string()).collect(),
+            "--port"          => self.port = Some(value.parse()?),
+            "--tcp-port"      => self.tcp_port = Some(value.parse()?),
+            "--udp-addr"      => self.udp_addr = Some(value.to_string()),
+            "--tcp-addr"      => self.tcp_addr = Some(value.to_string()),
+            "--advertise-udp-addr" => self.advertise_udp_addr = Some(value.to_string()),
+            "--advertise-tcp-addr" => self.advertise_tcp_addr = Some(value.to_string()),
+            "--log-dir"       => self.log_dir = value.to_string(),
+            "--data-dir"      => self.data_dir = value.to_string(),
+            "--replicas"      => self.num_owners = value.parse()?,
+            "--successors"    => self.num_successors = value.parse()?,
+            "--write-quorum"  => self.write_quorum = Some(value.parse()?),
+            "--read-quorum"   => self.read_quorum = Some(value.parse()?),
+            "--probe-interval" => self.probe_interval = value.parse()?,
+            "--ping-timeout"  => self.ping_timeout = value.parse()?,
+            "--suspect-timeout" => self.suspect_timeout = value.parse()?,
+            "--indirect-probes" => self.indirect_probes = value.parse()?,
+            "--quorum-timeout" => self.quorum_timeout = value.parse()?,
+            "--rpc-timeout"   => self.rpc_timeout = value.parse()?,
+            "--rpc-retries"   => self.rpc_retries = value.parse()?,
+            "--chunk-size"    => self.chunk_size = value.parse()?,
+            "--max-frame-size" => self.max_frame_size = value.parse()?,
+            "--hash-algorithm" => self.hash_algorithm = HashAlgorithm::from_name(value)?,
+            "--virtual-nodes" => self.virtual_nodes = value.parse()?,
+            "--weight"        => self.weight = value.parse()?,
+            "--gossip-interval" => self.gossip_interval = value.parse()?,
+            "--departed-member-ttl" => self.departed_member_ttl = value.parse()?,
+            "--file-tombstone-ttl" => self.file_tombstone_ttl = value.parse()?,
+            "--rebalance-interval" => self.rebalance_interval = value.parse()?,
+            "--rebalance-batch-size" => self.rebalance_batch_size = value.parse()?,
+            "--tls-ca-file"   => self.tls_ca_file = Some(value.to_string()),
+            "--tls-cert-file" => self.tls_cert_file = Some(value.to_string()),
+            "--tls-key-file"  => self.tls_key_file = Some(value.to_string()),
+            "--udp-secret-file" => self.udp_secret_file = Some(value.to_string()),
+            "--udp-replay-window" => self.udp_replay_window = value.parse()?,
+            _ => return Err(format!("Unrecognized flag {}", flag).into())
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self) -> BoxedErrorResult<()> {
+        if self.introducers.is_empty() {
+            return Err("At least one introducer is required".into())
+        }
+        if self.port.is_none() && self.udp_addr.is_none() {
+            return Err("Either a port or a UDP address is required".into())
+        }
+        for introducer in self.introducers.iter() {
+            parse_addr("introducer", introducer)?;
+        }
+        let addrs = [("udp_addr", &self.udp_addr), ("tcp_addr", &self.tcp_addr),
+                     ("advertise_udp_addr", &self.advertise_udp_addr),
+                     ("advertise_tcp_addr", &self.advertise_tcp_addr)];
+        for (name, addr) in addrs.iter() {
+            if let Some(addr) = addr {
+                parse_addr(name, addr)?;
+            }
+        }
+        if self.port == Some(0) || self.tcp_port == Some(0) {
+            return Err("Ports must be given explicitly - 0 would make the OS pick one nobody else knows".into())
+        }
+        if self.num_owners == 0 {
+            return Err("The replication factor must be at least 1".into())
+        }
+        for (name, quorum) in &[("write", self.write_quorum), ("read", self.read_quorum)] {
+            if let Some(quorum) = quorum {
+                if *quorum == 0 || *quorum > self.num_owners {
+                    return Err(format!("The {} quorum must be between 1 and the replication factor ({})",
+                                       name, self.num_owners).into())
+                }
+            }
+        }
+        if self.chunk_size == 0 {
+            return Err("The chunk size must be at least 1 byte".into())
+        }
+        // A chunk has to fit in a frame along with its header and length prefix
+        if self.chunk_size + constants::HEADER_SIZE + 8 > self.max_frame_size {
+            return Err(format!("The max frame size must be more than {} bytes to fit a chunk", self.chunk_size + constants::HEADER_SIZE + 8).into())
+        }
+        if self.ping_timeout >= self.probe_interval {
+            return Err("The ping timeout must be shorter than the probe interval to leave time for indirect probes".into())
+        }
+        if self.virtual_nodes == 0 || self.weight == 0 {
+            return Err("The number of virtual nodes and the weight must be at least 1".into())
+        }
+        if self.virtual_nodes > constants::MAX_VIRTUAL_NODES || self.weight > constants::MAX_WEIGHT {
+            return Err(format!("The number of virtual nodes can be at most {} and the weight at most {}",
+                               constants::MAX_VIRTUAL_NODES, constants::MAX_WEIGHT).into())
+        }
+        if self.gossip_interval == 0 {
+            return Err("The gossip interval must be at least 1 ms".into())
+        }
+        if self.departed_member_ttl <= self.gossip_interval {
+            return Err("Departed members must be remembered for longer than the gossip interval".into())
+        }
+        if self.file_tombstone_ttl <= self.gossip_interval {
+            return Err("Deleted files must be remembered for longer than the gossip interval".into())
+        }
+        if self.ping_timeout == 0 || self.suspect_timeout == 0 {
+            return Err("The ping and suspect timeouts must be at least 1 ms".into())
+        }
+        if self.rpc_timeout == 0 {
+            return Err("The RPC timeout must be at least 1 ms".into())
+        }
+        if self.rebalance_batch_size == 0 {
+            return Err("The rebalance batch size must be at least 1 file".into())
+        }
+        let tls_files = [&self.tls_ca_file, &self.tls_cert_file, &self.tls_key_file];
+        if tls_files.iter().any(|x| x.is_some()) && !tls_files.iter().all(|x| x.is_some()) {
+            return Err("TLS needs all of tls_ca_file, tls_cert_file and tls_key_file".into())
+        }
+        // Operations too big for a datagram go over TCP, which the secret does nothing for
+        if self.udp_secret_file.is_some() && self.tls_ca_file.is_none() {
+            return Err("udp_secret_file also needs TLS (tls_ca_file, tls_cert_file and tls_key_file) to cover the TCP fallback".into())
+        }
+        if self.udp_replay_window == 0 {
+            return Err("The UDP replay window must be at least 1 ms".into())
+        }
+        Ok(())
+    }
+}
+
+pub fn parse_addr(name: &str, addr: &str) -> BoxedErrorResult<SocketAddr> {
+    addr.parse::<SocketAddr>().map_err(|e| {
+        format!("Invalid {} {:?}: {} (expected IP:PORT, with IPv6 as [IP]:PORT)", name, addr, e).into()
+    })
+}
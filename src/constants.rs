@@ -1,4 +1,4 @@
-use crate::heartbeat::Timestamp;
+use crate::easyhash::HashAlgorithm;
 
 // House
 // pub const IP_LIST: [&str; 4] = [
@@ -27,11 +27,50 @@ pub const IP_LIST: [&str; 4] = [
 ];
 
 
+// Frame header - magic (2) | protocol version (2) | tag (OP_TYPE_SIZE) | frame size (4) | CRC32 (4)
+pub static MAGIC: [u8; 2] = *b"DF";
 pub static OP_TYPE_SIZE: usize = 4;
-pub static HEADER_SIZE: usize = 8;
+pub static HEADER_SIZE: usize = 16;
+// The most an IPv4 UDP datagram can carry
+pub static MAX_UDP_FRAME_SIZE: usize = 65507;
+// Bumped whenever the encoding of an operation changes. Frames are written at the version agreed on when joining
+// and read at any version from MIN_PROTOCOL_VERSION up to PROTOCOL_VERSION.
+pub static PROTOCOL_VERSION: u16 = 1;
+pub static MIN_PROTOCOL_VERSION: u16 = 1;
+// Appended to every datagram when a UDP secret is configured - timestamp (8) | nonce (8) | HMAC-SHA256 (MAC_SIZE)
+pub static MAC_SIZE: usize = 32;
+pub static AUTH_TRAILER_SIZE: usize = 16 + MAC_SIZE;
+pub static MIN_UDP_SECRET_SIZE: usize = 16;
+
+// Defaults for config.rs - override them with a config file or flags instead of editing these
 pub static NUM_SUCCESSORS: u32 = 2;
 pub static NUM_OWNERS: u32 = 2;
-pub static EXPIRATION_DURATION: Timestamp = 3;
+// None falls back to a majority of NUM_OWNERS
+pub static WRITE_QUORUM: Option<u32> = None;
+pub static READ_QUORUM: Option<u32> = None;
+pub static QUORUM_TIMEOUT: u64 = 5000; // ms
+pub static CHUNK_SIZE: usize = 1 << 20;
+pub static MAX_FRAME_SIZE: usize = 64 << 20;
+pub static HASH_ALGORITHM: HashAlgorithm = HashAlgorithm::XxHash64;
+pub static VIRTUAL_NODES: u32 = 64;
+pub static WEIGHT: u32 = 1;
+// Weights come off the wire, so they are capped to keep a peer from making everyone build a huge ring
+pub static MAX_WEIGHT: u32 = 64;
+pub static MAX_VIRTUAL_NODES: u32 = 1024;
+pub static GOSSIP_INTERVAL: u64 = 2000; // ms
+pub static DEPARTED_MEMBER_TTL: u64 = 600000; // ms
+pub static FILE_TOMBSTONE_TTL: u64 = 3600000; // ms
+pub static REBALANCE_INTERVAL: u64 = 1000; // ms
+pub static REBALANCE_BATCH_SIZE: usize = 4;
+pub static PROBE_INTERVAL: u64 = 1000; // ms
+pub static PING_TIMEOUT: u64 = 300; // ms
+pub static SUSPECT_TIMEOUT: u64 = 3000; // ms
+pub static INDIRECT_PROBES: u32 = 3;
+pub static RPC_TIMEOUT: u64 = 2000; // ms
+pub static RPC_RETRIES: u32 = 2;
+// Doubled after every failed attempt
+pub static RPC_RETRY_BACKOFF: u64 = 100; // ms
+pub static UDP_REPLAY_WINDOW: u64 = 30000; // ms
 
 pub static LOG_DIR: &str  = "logs";
 pub static DATA_DIR: &str = "data";
@@ -1,17 +1,54 @@
-use std::collections::hash_map::DefaultHasher;
+use crate::BoxedErrorResult;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::convert::TryInto;
 use std::fmt::UpperHex;
-use std::hash::{Hash, Hasher};
+use std::hash::Hasher;
+use twox_hash::XxHash64;
+
+// Placement depends on every node hashing names the same way, so only specified algorithms are allowed here -
+// std's DefaultHasher is free to change between Rust releases
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    // xxHash64 with a seed of 0
+    XxHash64,
+    // The first 8 bytes of the SHA-256 digest read as a big endian integer
+    Sha256
+}
+
+impl HashAlgorithm {
+    pub fn from_name(name: &str) -> BoxedErrorResult<Self> {
+        match name {
+            "xxhash64" => Ok(HashAlgorithm::XxHash64),
+            "sha256"   => Ok(HashAlgorithm::Sha256),
+            _ => Err(format!("Unknown hash algorithm {}, expected xxhash64 or sha256", name).into())
+        }
+    }
+
+    pub fn hash(&self, bytes: &[u8]) -> u64 {
+        match self {
+            HashAlgorithm::XxHash64 => {
+                let mut hasher = XxHash64::with_seed(0);
+                hasher.write(bytes);
+                hasher.finish()
+            },
+            HashAlgorithm::Sha256 => {
+                let digest = Sha256::digest(bytes);
+                u64::from_be_bytes(digest[..8].try_into().unwrap())
+            }
+        }
+    }
+}
 
 pub trait EasyHash {
-    fn easyhash(&self) -> u64;
+    fn easyhash(&self, algorithm: HashAlgorithm) -> u64;
 }
 
 impl<T> EasyHash for T
-where T: Hash {
-    fn easyhash(&self) -> u64 {
-        let mut hasher = DefaultHasher::new();
-        self.hash(&mut hasher);
-        hasher.finish()
+where T: AsRef<[u8]> + ?Sized {
+    fn easyhash(&self, algorithm: HashAlgorithm) -> u64 {
+        algorithm.hash(self.as_ref())
     }
 }
 
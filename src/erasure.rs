@@ -0,0 +1,464 @@
+use crate::BoxedErrorResult;
+use crate::component_manager::*;
+use crate::filesystem::{self, FileMetadata, NewFileOwnersOperation, Version};
+use crate::globals;
+use crate::operation::*;
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use serde::{Serialize, Deserialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{Read, Write};
+
+// Reed-Solomon erasure coding for large files. The file is split into data_shards pieces plus parity_shards
+// parity pieces, and each one is stored as its own single owner distributed file named parent#index on a
+// distinct member. Any data_shards of them rebuild the file, so it survives parity_shards failures while
+// only taking up (k + m) / k times its size instead of num_owners times.
+
+// Recorded in the metadata of every shard
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ShardInfo {
+    pub parent: String,
+    pub index: u32,
+    pub data_shards: u32,
+    pub parity_shards: u32,
+    // Bytes of every shard per stripe, fixed when the file is first put
+    pub stripe_size: u32
+}
+
+impl ShardInfo {
+    pub fn total_shards(&self) -> u32 {
+        self.data_shards + self.parity_shards
+    }
+}
+
+pub fn shard_name(parent: &str, index: u32) -> String {
+    format!("{}#{}", parent, index)
+}
+
+// k+m, e.g. 4+2
+pub fn parse_scheme(scheme: &str) -> BoxedErrorResult<(u32, u32)> {
+    let mut parts = scheme.splitn(2, '+');
+    let data_shards: u32 = parts.next().unwrap_or("").parse()?;
+    let parity_shards: u32 = parts.next().ok_or("Erasure scheme must look like K+M")?.parse()?;
+    if data_shards == 0 || parity_shards == 0 {
+        return Err("Erasure scheme needs at least one data shard and one parity shard".into())
+    }
+    Ok((data_shards, parity_shards))
+}
+
+// The shard layout of the file if it was put erasure coded - every shard records it, and any of them may be the
+// one left after deletes or failures, so they are all looked at
+pub fn find_shard_info(parent: &str) -> Option<ShardInfo> {
+    globals::ALL_FILE_OWNERS.read()
+        .values()
+        .filter_map(|metadata| metadata.erasure.as_ref())
+        .find(|info| info.parent == parent)
+        .map(|info| ShardInfo { index: 0, ..info.clone() })
+}
+
+// The payload is the length followed by the data so that the padding of the last stripe can be stripped again.
+// It is coded a stripe at a time, each one giving every shard up to stripe_size bytes, so that only one stripe
+// is ever held in memory. Payloads smaller than a full stripe shrink it so that small files aren't padded out.
+pub fn encode<R: Read, W: Write>(input: &mut R, len: u64, info: &ShardInfo, outputs: &mut [W]) -> BoxedErrorResult<()> {
+    let codec = ReedSolomon::new(info.data_shards as usize, info.parity_shards as usize)?;
+    if info.stripe_size < 8 {
+        return Err("The stripe size must be at least 8 bytes to fit the length".into())
+    }
+    if outputs.len() != codec.total_shard_count() {
+        return Err(format!("Encoding needs {} outputs but got {}", codec.total_shard_count(), outputs.len()).into())
+    }
+    let piece_size = std::cmp::min(info.stripe_size as u64, (len + 8).div_ceil(info.data_shards as u64)) as usize;
+    let mut remaining = len;
+    let mut stripe: Vec<u8> = vec![0; piece_size * info.data_shards as usize];
+    stripe[..8].copy_from_slice(&len.to_be_bytes());
+    let mut offset = 8;
+    loop {
+        let num_read = std::cmp::min(remaining, (stripe.len() - offset) as u64) as usize;
+        input.read_exact(&mut stripe[offset..offset + num_read])?;
+        stripe[offset + num_read..].iter_mut().for_each(|x| *x = 0);
+        remaining -= num_read as u64;
+        let mut pieces: Vec<Vec<u8>> = stripe.chunks(piece_size).map(|x| x.to_vec()).collect();
+        pieces.resize(codec.total_shard_count(), vec![0; piece_size]);
+        codec.encode(&mut pieces)?;
+        for (piece, output) in pieces.iter().zip(outputs.iter_mut()) {
+            output.write_all(piece)?;
+        }
+        if remaining == 0 {
+            return Ok(())
+        }
+        offset = 0;
+    }
+}
+
+// Rebuilds the original data out of any data_shards of the shards, each shard_size bytes long
+pub fn decode<R: Read, W: Write>(inputs: Vec<Option<R>>, shard_size: u64, info: &ShardInfo, output: &mut W) -> BoxedErrorResult<()> {
+    let mut remaining: Option<u64> = None;
+    for_each_stripe(inputs, shard_size, info, false, |pieces| {
+        let mut stripe: Vec<u8> = Vec::new();
+        for piece in pieces.into_iter().take(info.data_shards as usize) {
+            stripe.append(&mut piece.ok_or("Data shard missing after reconstruction")?);
+        }
+        // The first stripe starts with the length
+        let data = match remaining {
+            Some(_) => &stripe[..],
+            None if stripe.len() < 8 => return Err("Erasure coded payload is too short".into()),
+            None => {
+                let mut len_bytes = [0; 8];
+                len_bytes.copy_from_slice(&stripe[..8]);
+                remaining = Some(u64::from_be_bytes(len_bytes));
+                &stripe[8..]
+            }
+        };
+        let num_written = std::cmp::min(remaining.unwrap(), data.len() as u64);
+        output.write_all(&data[..num_written as usize])?;
+        remaining = remaining.map(|x| x - num_written);
+        Ok(())
+    })?;
+    match remaining {
+        Some(0) => Ok(()),
+        Some(missing) => Err(format!("Erasure coded payload is {} bytes short", missing).into()),
+        None => Err("Erasure coded payload is too short".into())
+    }
+}
+
+// Rebuilds shard index out of any data_shards of the others, each shard_size bytes long
+pub fn rebuild<R: Read, W: Write>(inputs: Vec<Option<R>>, shard_size: u64, info: &ShardInfo, index: u32, output: &mut W) -> BoxedErrorResult<()> {
+    for_each_stripe(inputs, shard_size, info, true, |mut pieces| {
+        output.write_all(&pieces.swap_remove(index as usize).ok_or("Shard missing after reconstruction")?)?;
+        Ok(())
+    })
+}
+
+// Reads the shards a stripe at a time and hands each one on with its missing data pieces, or all of them, filled in
+fn for_each_stripe<R, F>(mut inputs: Vec<Option<R>>, shard_size: u64, info: &ShardInfo, with_parity: bool, mut f: F) -> BoxedErrorResult<()>
+where R: Read, F: FnMut(Vec<Option<Vec<u8>>>) -> BoxedErrorResult<()> {
+    let codec = ReedSolomon::new(info.data_shards as usize, info.parity_shards as usize)?;
+    if inputs.len() != codec.total_shard_count() {
+        return Err(format!("Decoding needs {} inputs but got {}", codec.total_shard_count(), inputs.len()).into())
+    }
+    let piece_size = std::cmp::min(info.stripe_size as u64, shard_size);
+    if piece_size == 0 || !shard_size.is_multiple_of(piece_size) {
+        return Err(format!("Shards of {} bytes can't hold stripes of {} bytes", shard_size, info.stripe_size).into())
+    }
+    for _ in 0..shard_size / piece_size {
+        let mut pieces: Vec<Option<Vec<u8>>> = Vec::with_capacity(inputs.len());
+        for input in inputs.iter_mut() {
+            pieces.push(match input {
+                Some(input) => {
+                    let mut piece = vec![0; piece_size as usize];
+                    input.read_exact(&mut piece)?;
+                    Some(piece)
+                },
+                None => None
+            });
+        }
+        match with_parity {
+            true  => codec.reconstruct(&mut pieces)?,
+            false => codec.reconstruct_data(&mut pieces)?
+        }
+        f(pieces)?;
+    }
+    Ok(())
+}
+
+// Shard index goes to the index-th member in ring order from the parent's hash, so they all land on distinct owners
+pub fn desired_shard_owner(info: &ShardInfo) -> BoxedErrorResult<Vec<String>> {
+    let owners = globals::HASH_RING.read().owners(&info.parent, info.total_shards() as usize);
+    if owners.is_empty() {
+        return Err("Cannot place a shard because the membership list is empty".into())
+    }
+    Ok(vec![owners[info.index as usize % owners.len()].clone()])
+}
+
+// The first member in ring order that doesn't already hold a sibling shard, so one more failure still only costs one shard
+pub fn gen_new_shard_owner(info: &ShardInfo) -> Vec<String> {
+    let taken = sibling_owners(info, false);
+    let num_members = globals::MEMBERSHIP_LIST.read().len();
+    let candidates = globals::HASH_RING.read().owners(&info.parent, num_members);
+    candidates.iter()
+        .find(|x| !taken.contains(*x))
+        .or(candidates.first())
+        .cloned()
+        .into_iter()
+        .collect()
+}
+
+// The owners of all the shards of the file, optionally including this shard's own
+pub fn sibling_owners(info: &ShardInfo, include_self: bool) -> HashSet<String> {
+    let all_file_owners = globals::ALL_FILE_OWNERS.read();
+    (0..info.total_shards())
+        .filter(|index| include_self || *index != info.index)
+        .filter_map(|index| all_file_owners.get(&shard_name(&info.parent, index)))
+        .flat_map(|metadata| metadata.owners.iter().cloned())
+        .collect()
+}
+
+// Encodes the local file and sends every shard to its own owner, succeeding once enough of them can rebuild it
+pub fn put(local_path: &str, distributed_filename: &str, data_shards: u32, parity_shards: u32, sender: &OperationSender) -> BoxedErrorResult<()> {
+    filesystem::check_distributed_filename(distributed_filename)?;
+    if globals::ALL_FILE_OWNERS.read().contains_key(distributed_filename) {
+        return Err(format!("{} is already stored replicated", distributed_filename).into())
+    }
+    let existing_info = find_shard_info(distributed_filename);
+    if let Some(info) = &existing_info {
+        if (info.data_shards, info.parity_shards) != (data_shards, parity_shards) {
+            return Err(format!("{} is already erasure coded as {}+{}", distributed_filename, info.data_shards, info.parity_shards).into())
+        }
+    }
+    let total_shards = data_shards + parity_shards;
+    let ring_owners = globals::HASH_RING.read().owners(distributed_filename, total_shards as usize);
+    if ring_owners.len() < total_shards as usize {
+        return Err(format!("Erasure coding as {}+{} needs at least {} members but there are {}",
+                           data_shards, parity_shards, total_shards, ring_owners.len()).into())
+    }
+    let version = filesystem::next_version(distributed_filename, &ring_owners)?;
+    let stripe_size = match &existing_info {
+        Some(info) => info.stripe_size,
+        None => std::cmp::max(globals::CONFIG.read().chunk_size, 8) as u32
+    };
+    // Every shard is coded into its own scratch file first, a stripe at a time
+    let shard_paths: Vec<String> = (0..total_shards)
+        .map(|index| format!("{}/.{}.encoded", globals::CONFIG.read().data_dir, shard_name(distributed_filename, index)))
+        .collect();
+    let mut info = ShardInfo {
+        parent: distributed_filename.to_string(),
+        index: 0,
+        data_shards,
+        parity_shards,
+        stripe_size
+    };
+    if let Err(e) = encode_file(local_path, &info, &shard_paths) {
+        remove_scratch_files(shard_paths.iter());
+        return Err(e)
+    }
+    let mut num_stored = 0;
+    for (index, shard_path) in shard_paths.iter().enumerate() {
+        let name = shard_name(distributed_filename, index as u32);
+        info.index = index as u32;
+        // Existing shards keep all of their versions together, like replicated files do
+        let owner = match globals::ALL_FILE_OWNERS.read().get(&name) {
+            Some(metadata) if !metadata.owners.is_empty() => metadata.owners.iter().min().cloned().unwrap(),
+            _ => ring_owners[index].clone()
+        };
+        let size = std::fs::metadata(shard_path)?.len();
+        let digest = filesystem::digest_of_file(shard_path)?;
+        let result = async_std::task::block_on(filesystem::send_file_to_all(shard_path.clone(),
+                                                                            size,
+                                                                            digest.clone(),
+                                                                            name.clone(),
+                                                                            version,
+                                                                            std::slice::from_ref(&owner),
+                                                                            1));
+        std::fs::remove_file(shard_path)?;
+        // Like a replicated put, a shard is only gossiped once it is stored
+        match result {
+            Ok(()) => {
+                sender.send(
+                    SendableOperation::for_successors(Box::new(NewFileOwnersOperation {
+                        distributed_filename: name.clone(),
+                        new_owners: vec![owner].into_iter().collect(),
+                        from_failure: false,
+                        version,
+                        digest: Some(digest),
+                        replicas: 1,
+                        erasure: Some(info.clone())
+                    }))
+                )?;
+                num_stored += 1;
+            },
+            Err(e) => log(format!("Could not store shard {}@{}: {}", name, version, e))?
+        }
+    }
+    if num_stored < data_shards {
+        return Err(format!("Only stored {}/{} shards of {}@{}, {} are needed to read it back",
+                           num_stored, total_shards, distributed_filename, version, data_shards).into())
+    }
+    if num_stored < total_shards {
+        println!("Stored {}/{} shards of {}@{}", num_stored, total_shards, distributed_filename, version);
+    }
+    Ok(())
+}
+
+fn encode_file(local_path: &str, info: &ShardInfo, shard_paths: &[String]) -> BoxedErrorResult<()> {
+    let mut input = File::open(local_path)?;
+    let len = input.metadata()?.len();
+    let mut outputs = shard_paths.iter().map(File::create).collect::<Result<Vec<File>, _>>()?;
+    encode(&mut input, len, info, &mut outputs)
+}
+
+// Fetches shards until data_shards of them arrived, then rebuilds the file into local_path
+pub fn get(info: &ShardInfo, version: Version, local_path: &String) -> BoxedErrorResult<()> {
+    let shard_paths = fetch_shards(info, version, None, local_path)?;
+    let part_path = format!("{}.part", local_path);
+    let result = open_shards(&shard_paths).and_then(|(inputs, shard_size)| {
+        decode(inputs, shard_size, info, &mut File::create(&part_path)?)
+    });
+    remove_scratch_files(shard_paths.iter().flatten());
+    match result {
+        Ok(()) => Ok(std::fs::rename(&part_path, local_path)?),
+        Err(e) => {
+            let _ = std::fs::remove_file(&part_path);
+            Err(e)
+        }
+    }
+}
+
+// Data shards are asked for first since rebuilding out of them alone is just stitching them together.
+// Each one lands in its own scratch file next to scratch_path, to be removed by the caller.
+fn fetch_shards(info: &ShardInfo, version: Version, skip: Option<u32>, scratch_path: &String) -> BoxedErrorResult<Vec<Option<String>>> {
+    let mut shard_paths: Vec<Option<String>> = vec![None; info.total_shards() as usize];
+    let mut num_fetched = 0;
+    for index in 0..info.total_shards() {
+        if num_fetched == info.data_shards {
+            break;
+        }
+        if skip == Some(index) {
+            continue;
+        }
+        let name = shard_name(&info.parent, index);
+        let shard_path = format!("{}.shard{}", scratch_path, index);
+        let result = async_std::task::block_on(filesystem::fetch_distributed_file(&name, Some(version), 1, &shard_path));
+        match result {
+            Ok(_) => {
+                shard_paths[index as usize] = Some(shard_path);
+                num_fetched += 1;
+            },
+            Err(e) => log(format!("Could not fetch shard {}@{}: {}", name, version, e))?
+        }
+    }
+    if num_fetched < info.data_shards {
+        remove_scratch_files(shard_paths.iter().flatten());
+        return Err(format!("Only found {}/{} shards of {}@{}, {} are needed to rebuild it",
+                           num_fetched, info.total_shards(), info.parent, version, info.data_shards).into())
+    }
+    Ok(shard_paths)
+}
+
+// The fetched shards along with their size, which has to be the same for all of them
+fn open_shards(shard_paths: &[Option<String>]) -> BoxedErrorResult<(Vec<Option<File>>, u64)> {
+    let mut inputs: Vec<Option<File>> = Vec::new();
+    let mut shard_size: Option<u64> = None;
+    for shard_path in shard_paths {
+        let input = match shard_path {
+            Some(shard_path) => Some(File::open(shard_path)?),
+            None => None
+        };
+        if let Some(input) = &input {
+            let size = input.metadata()?.len();
+            if let Some(shard_size) = shard_size.filter(|shard_size| *shard_size != size) {
+                return Err(format!("Shards differ in size, {} and {} bytes", shard_size, size).into())
+            }
+            shard_size = Some(size);
+        }
+        inputs.push(input);
+    }
+    Ok((inputs, shard_size.unwrap_or(0)))
+}
+
+fn remove_scratch_files<'a>(paths: impl Iterator<Item = &'a String>) {
+    for path in paths {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+// Decoding pulls in k shards per version, which would keep the receiver from answering pings for too long
+pub fn spawn_regenerate_shard(distributed_filename: String) {
+    std::thread::spawn(move || {
+        if let Err(e) = regenerate_shard(&distributed_filename) {
+            let _ = log(format!("Could not regenerate shard {}: {}", distributed_filename, e));
+        }
+    });
+}
+
+// Rebuilds every version of a lost shard out of its siblings - used by the new owner picked after a failure
+fn regenerate_shard(distributed_filename: &String) -> BoxedErrorResult<()> {
+    let metadata: FileMetadata = match globals::ALL_FILE_OWNERS.read().get(distributed_filename) {
+        Some(metadata) => metadata.clone(),
+        None => return Err(format!("No owners found for file {}", distributed_filename).into())
+    };
+    let info = metadata.erasure.clone().ok_or(format!("{} is not a shard", distributed_filename))?;
+    let held_versions = filesystem::local_versions(distributed_filename)?;
+    for version in 1..=metadata.version {
+        if held_versions.contains(&version) {
+            continue;
+        }
+        match regenerate_shard_version(&info, &metadata, version) {
+            Ok(()) => log(format!("Regenerated shard {}@{}", distributed_filename, version))?,
+            Err(e) => log(format!("Could not regenerate shard {}@{}: {}", distributed_filename, version, e))?
+        }
+    }
+    Ok(())
+}
+
+fn regenerate_shard_version(info: &ShardInfo, metadata: &FileMetadata, version: Version) -> BoxedErrorResult<()> {
+    let name = shard_name(&info.parent, info.index);
+    let local_path = filesystem::distributed_file_path(&name, version);
+    let scratch_path = format!("{}/.{}@{}.rebuild", globals::CONFIG.read().data_dir, name, version);
+    let shard_paths = fetch_shards(info, version, Some(info.index), &scratch_path)?;
+    let part_path = format!("{}.part", local_path);
+    let result = open_shards(&shard_paths).and_then(|(inputs, shard_size)| {
+        rebuild(inputs, shard_size, info, info.index, &mut File::create(&part_path)?)
+    });
+    remove_scratch_files(shard_paths.iter().flatten());
+    if let Err(e) = result {
+        let _ = std::fs::remove_file(&part_path);
+        return Err(e)
+    }
+    let digest = filesystem::digest_of_file(&part_path)?;
+    // A rebuilt shard nobody recorded a digest for can't be told apart from garbage
+    match metadata.digests.get(&version) {
+        Some(recorded_digest) if *recorded_digest == digest => (),
+        Some(recorded_digest) => {
+            std::fs::remove_file(&part_path)?;
+            return Err(format!("Checksum mismatch, expected {} but got {}", recorded_digest, digest).into())
+        },
+        None => {
+            std::fs::remove_file(&part_path)?;
+            return Err(format!("No digest recorded for {}@{} to check the rebuilt shard against", name, version).into())
+        }
+    }
+    std::fs::rename(&part_path, &local_path)?;
+    std::fs::write(filesystem::digest_path(&local_path), &digest)?;
+    Ok(())
+}
+
+// Shards are written one at a time, so the newest version any of them has heard of
+pub fn latest_version(info: &ShardInfo) -> Option<Version> {
+    let all_file_owners = globals::ALL_FILE_OWNERS.read();
+    (0..info.total_shards())
+        .filter_map(|index| all_file_owners.get(&shard_name(&info.parent, index)))
+        .map(|metadata| metadata.version)
+        .max()
+}
+
+// The versions enough shards were committed for to rebuild them
+pub fn committed_versions(info: &ShardInfo) -> Vec<Version> {
+    let all_file_owners = globals::ALL_FILE_OWNERS.read();
+    let mut num_shards: HashMap<Version, u32> = HashMap::new();
+    for metadata in (0..info.total_shards()).filter_map(|index| all_file_owners.get(&shard_name(&info.parent, index))) {
+        for version in metadata.digests.keys() {
+            *num_shards.entry(*version).or_default() += 1;
+        }
+    }
+    num_shards.into_iter()
+        .filter(|(_, num_shards)| *num_shards >= info.data_shards)
+        .map(|(version, _)| version)
+        .collect()
+}
+
+pub fn shard_names(info: &ShardInfo) -> Vec<String> {
+    (0..info.total_shards()).map(|index| shard_name(&info.parent, index)).collect()
+}
+
+pub fn print(info: &ShardInfo) -> BoxedErrorResult<()> {
+    let all_file_owners = globals::ALL_FILE_OWNERS.read();
+    println!("Erasure coded as {}+{}", info.data_shards, info.parity_shards);
+    for name in shard_names(info) {
+        match all_file_owners.get(&name) {
+            Some(metadata) => println!("{}: {:?}", name, metadata),
+            None => println!("{}: {{}}", name)
+        }
+    }
+    Ok(())
+}
+
@@ -0,0 +1,395 @@
+use crate::BoxedErrorResult;
+use crate::component_manager::*;
+use crate::globals;
+use crate::heartbeat::{self, Incarnation, LeaveOperation};
+use crate::operation::*;
+use rand::seq::SliceRandom;
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::time::{Duration, Instant};
+
+// SWIM-style failure detection: every protocol period one member is pinged directly, then indirectly through
+// a few others if it stays quiet, and only declared dead after sitting in the suspect state for a while.
+// Membership changes ride along on the pings and acks instead of needing packets of their own.
+// Every update names the incarnation it is about, so a member that hears it is suspected or dead can outbid it.
+
+// How many updates fit on a single ping/ack
+static MAX_PIGGYBACKED_UPDATES: usize = 6;
+// Updates are retransmitted RETRANSMIT_MULTIPLIER * log2(n) times so they reach everyone with high probability
+static RETRANSMIT_MULTIPLIER: u32 = 3;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum MemberUpdate {
+    Alive { id: String, incarnation: Incarnation },
+    Suspect { id: String, incarnation: Incarnation },
+    Dead { id: String, incarnation: Incarnation }
+}
+
+impl MemberUpdate {
+    fn id(&self) -> &String {
+        match self {
+            MemberUpdate::Alive { id, .. } | MemberUpdate::Suspect { id, .. } | MemberUpdate::Dead { id, .. } => id
+        }
+    }
+}
+
+struct Probe {
+    target: String,
+    seq: u64,
+    started: Instant,
+    acked: bool,
+    indirect_sent: bool
+}
+
+pub struct DetectorState {
+    seq: u64,
+    probe: Option<Probe>,
+    probe_order: Vec<String>,
+    suspects: HashMap<String, Instant>,
+    last_seen: HashMap<String, Instant>,
+    updates: Vec<(MemberUpdate, u32)>
+}
+
+impl DetectorState {
+    pub fn new() -> Self {
+        DetectorState {
+            seq: 0,
+            probe: None,
+            probe_order: Vec::new(),
+            suspects: HashMap::new(),
+            last_seen: HashMap::new(),
+            updates: Vec::new()
+        }
+    }
+}
+
+pub fn detector(sender: &OperationSender) -> ComponentResult {
+    if !is_joined() {
+        return Ok(())
+    }
+    let (probe_interval, ping_timeout, suspect_timeout) = {
+        let config = globals::CONFIG.read();
+        (Duration::from_millis(config.probe_interval),
+         Duration::from_millis(config.ping_timeout),
+         Duration::from_millis(config.suspect_timeout))
+    };
+    // Suspects that never refuted are dead
+    let expired_suspects: Vec<String> = globals::DETECTOR_STATE.read().suspects
+        .iter()
+        .filter(|(_, since)| since.elapsed() > suspect_timeout)
+        .map(|(id, _)| id.clone())
+        .collect();
+    for expired_suspect in expired_suspects {
+        log(format!("Detected a failure on node {}", &expired_suspect))?;
+        for generated_operation in confirm_dead(&expired_suspect)? {
+            sender.send(generated_operation)?;
+        }
+    }
+    // Move the current probe along
+    let mut state = globals::DETECTOR_STATE.get_mut();
+    let (target, seq, elapsed, acked, indirect_sent) = match &state.probe {
+        Some(probe) => (probe.target.clone(), probe.seq, probe.started.elapsed(), probe.acked, probe.indirect_sent),
+        None => {
+            drop(state);
+            return start_probe();
+        }
+    };
+    if elapsed >= probe_interval {
+        state.probe = None;
+        drop(state);
+        if !acked && heartbeat::is_member(&target) {
+            suspect(&target)?;
+        }
+        return start_probe();
+    }
+    if !acked && !indirect_sent && elapsed >= ping_timeout {
+        if let Some(probe) = state.probe.as_mut() {
+            probe.indirect_sent = true;
+        }
+        drop(state);
+        send_ping_reqs(&target, seq)?;
+    }
+    Ok(())
+}
+
+// Picks the next member in a shuffled round robin so every member is probed within a bounded time
+fn start_probe() -> ComponentResult {
+    let mut state = globals::DETECTOR_STATE.get_mut();
+    let my_id = globals::MY_ID.read().clone();
+    let target = loop {
+        if state.probe_order.is_empty() {
+            let mut probe_order: Vec<String> = globals::MEMBERSHIP_LIST.read()
+                .iter()
+                .filter(|x| **x != my_id)
+                .cloned()
+                .collect();
+            if probe_order.is_empty() {
+                return Ok(())
+            }
+            probe_order.shuffle(&mut rand::thread_rng());
+            state.probe_order = probe_order;
+        }
+        let candidate = state.probe_order.pop().unwrap();
+        if heartbeat::is_member(&candidate) {
+            break candidate;
+        }
+    };
+    state.seq += 1;
+    let seq = state.seq;
+    state.probe = Some(Probe {
+        target: target.clone(),
+        seq,
+        started: Instant::now(),
+        acked: false,
+        indirect_sent: false
+    });
+    drop(state);
+    send_now(SendableOperation::for_single(target, Box::new(PingOperation {
+        seq,
+        requester: my_id,
+        updates: take_updates()
+    })))
+}
+
+fn send_ping_reqs(target: &String, seq: u64) -> ComponentResult {
+    let my_id = globals::MY_ID.read().clone();
+    let num_indirect_probes = globals::CONFIG.read().indirect_probes as usize;
+    let helpers: Vec<String> = globals::MEMBERSHIP_LIST.read()
+        .iter()
+        .filter(|x| **x != my_id && *x != target)
+        .cloned()
+        .collect::<Vec<String>>()
+        .choose_multiple(&mut rand::thread_rng(), num_indirect_probes)
+        .cloned()
+        .collect();
+    log(format!("No ack from {}, probing it through {:?}", target, helpers))?;
+    if helpers.is_empty() {
+        return Ok(())
+    }
+    send_now(SendableOperation::for_id_list(helpers, Box::new(PingReqOperation {
+        seq,
+        target: target.clone(),
+        requester: my_id,
+        updates: take_updates()
+    })))
+}
+
+// Probes can't wait on the sender's queue, so they go straight out on the socket
+fn send_now(operation: SendableOperation) -> ComponentResult {
+    operation.write_all_udp(&globals::UDP_SOCKET.read())
+}
+
+fn suspect(id: &String) -> ComponentResult {
+    let mut state = globals::DETECTOR_STATE.get_mut();
+    if !state.suspects.contains_key(id) {
+        state.suspects.insert(id.clone(), Instant::now());
+        drop(state);
+        let incarnation = heartbeat::incarnation_of(id).unwrap_or(0);
+        log(format!("Suspecting node {} at incarnation {}", id, incarnation))?;
+        enqueue_update(MemberUpdate::Suspect { id: id.clone(), incarnation });
+    }
+    Ok(())
+}
+
+fn confirm_dead(id: &String) -> BoxedErrorResult<Vec<SendableOperation>> {
+    let incarnation = heartbeat::incarnation_of(id).unwrap_or(0);
+    globals::DETECTOR_STATE.get_mut().suspects.remove(id);
+    enqueue_update(MemberUpdate::Dead { id: id.clone(), incarnation });
+    LeaveOperation { id: id.clone(), incarnation }.execute(Source::myself())
+}
+
+// Only the member itself announces it is alive - everyone else just stops suspecting it once they hear a newer incarnation
+pub fn announce_alive(id: &str, incarnation: Incarnation) {
+    enqueue_update(MemberUpdate::Alive { id: id.to_string(), incarnation });
+}
+
+pub fn clear_suspicion(id: &String) {
+    let was_suspected = globals::DETECTOR_STATE.get_mut().suspects.remove(id).is_some();
+    if was_suspected {
+        let _ = log(format!("Node {} is alive after all", id));
+    }
+}
+
+// Anything a member sends us first hand proves it was alive at that point
+fn saw(id: &String) {
+    if heartbeat::is_member(id) {
+        globals::DETECTOR_STATE.get_mut().last_seen.insert(id.clone(), Instant::now());
+    }
+}
+
+pub fn last_seen(id: &String) -> Option<Duration> {
+    globals::DETECTOR_STATE.read().last_seen.get(id).map(|seen| seen.elapsed())
+}
+
+pub fn forget_member(id: &String) {
+    let mut state = globals::DETECTOR_STATE.get_mut();
+    state.suspects.remove(id);
+    state.last_seen.remove(id);
+    state.probe_order.retain(|x| x != id);
+}
+
+pub fn reset() {
+    globals::DETECTOR_STATE.write(DetectorState::new());
+}
+
+fn enqueue_update(update: MemberUpdate) {
+    let mut state = globals::DETECTOR_STATE.get_mut();
+    state.updates.retain(|(queued, _)| queued.id() != update.id());
+    state.updates.push((update, 0));
+}
+
+// The least transmitted updates go first and are dropped once they have been sent enough times
+fn take_updates() -> Vec<MemberUpdate> {
+    let num_members = globals::MEMBERSHIP_LIST.read().len() as f64;
+    let retransmit_limit = RETRANSMIT_MULTIPLIER * ((num_members + 1.0).log2().ceil() as u32).max(1);
+    let mut state = globals::DETECTOR_STATE.get_mut();
+    state.updates.sort_by_key(|(_, transmissions)| *transmissions);
+    let mut taken = Vec::new();
+    for (update, transmissions) in state.updates.iter_mut().take(MAX_PIGGYBACKED_UPDATES) {
+        taken.push(update.clone());
+        *transmissions += 1;
+    }
+    state.updates.retain(|(_, transmissions)| *transmissions < retransmit_limit);
+    taken
+}
+
+fn apply_updates(updates: &Vec<MemberUpdate>) -> BoxedErrorResult<Vec<SendableOperation>> {
+    let mut generated_operations: Vec<SendableOperation> = Vec::new();
+    if !is_joined() {
+        return Ok(generated_operations)
+    }
+    let my_id = globals::MY_ID.read().clone();
+    for update in updates {
+        match update {
+            // Refute suspicions and evictions of ourselves by outbidding their incarnation
+            MemberUpdate::Suspect { id, incarnation } | MemberUpdate::Dead { id, incarnation }
+                if *id == my_id && *incarnation >= heartbeat::my_incarnation() => {
+                generated_operations.append(&mut heartbeat::refute(*incarnation)?);
+            },
+            // Older rumours about ourselves were already outbid and must not reach the arms below
+            MemberUpdate::Suspect { id, .. } | MemberUpdate::Dead { id, .. } if *id == my_id => {},
+            MemberUpdate::Suspect { id, incarnation } if heartbeat::is_member(id) && !is_outdated(id, *incarnation) => {
+                heartbeat::observe_incarnation(id, *incarnation);
+                suspect(id)?;
+            },
+            MemberUpdate::Alive { id, incarnation }
+                if heartbeat::is_member(id) && heartbeat::observe_incarnation(id, *incarnation) => {
+                clear_suspicion(id);
+                enqueue_update(update.clone());
+            },
+            MemberUpdate::Dead { id, incarnation } if heartbeat::is_member(id) && !is_outdated(id, *incarnation) => {
+                log(format!("Heard that node {} failed", id))?;
+                heartbeat::observe_incarnation(id, *incarnation);
+                generated_operations.append(&mut confirm_dead(id)?);
+            },
+            _ => {}
+        }
+    }
+    Ok(generated_operations)
+}
+
+fn is_outdated(id: &String, incarnation: Incarnation) -> bool {
+    heartbeat::incarnation_of(id).is_some_and(|known| incarnation < known)
+}
+
+// Operations
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PingOperation {
+    pub seq: u64,
+    pub requester: String,
+    pub updates: Vec<MemberUpdate>
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PingReqOperation {
+    pub seq: u64,
+    pub target: String,
+    pub requester: String,
+    pub updates: Vec<MemberUpdate>
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AckOperation {
+    pub seq: u64,
+    pub id: String,
+    pub incarnation: Incarnation,
+    pub requester: String,
+    pub updates: Vec<MemberUpdate>
+}
+
+// Trait Impls
+impl OperationWriteExecute for PingOperation {
+    fn to_bytes(&self) -> BoxedErrorResult<Vec<u8>> {
+        Ok(create_buf(&self, str_to_vec(Self::TAG)))
+    }
+    fn execute(&self, source: Source) -> BoxedErrorResult<Vec<SendableOperation>> {
+        let mut generated_operations = apply_updates(&self.updates)?;
+        saw(&self.requester);
+        // Ack whoever sent the ping - for indirect probes that is the helper, which relays it back
+        let source_addr: String = source.try_into()?;
+        let mut updates = take_updates();
+        // A requester we evicted might have missed the news, so tell it directly to give it a chance to refute
+        if !heartbeat::is_member(&self.requester) {
+            if let Some(incarnation) = heartbeat::incarnation_of(&self.requester) {
+                updates.push(MemberUpdate::Dead { id: self.requester.clone(), incarnation });
+            }
+        }
+        generated_operations.push(SendableOperation {
+            dests: Destinations::UDPAddr(vec![source_addr]),
+            operation: Box::new(AckOperation {
+                seq: self.seq,
+                id: globals::MY_ID.read().clone(),
+                incarnation: heartbeat::my_incarnation(),
+                requester: self.requester.clone(),
+                updates
+            })
+        });
+        Ok(generated_operations)
+    }
+    fn to_string(&self) -> String { format!("{:?}", self) }
+}
+
+impl OperationWriteExecute for PingReqOperation {
+    fn to_bytes(&self) -> BoxedErrorResult<Vec<u8>> {
+        Ok(create_buf(&self, str_to_vec(Self::TAG)))
+    }
+    fn execute(&self, _source: Source) -> BoxedErrorResult<Vec<SendableOperation>> {
+        let mut generated_operations = apply_updates(&self.updates)?;
+        saw(&self.requester);
+        generated_operations.push(SendableOperation::for_single(self.target.clone(), Box::new(PingOperation {
+            seq: self.seq,
+            requester: self.requester.clone(),
+            updates: take_updates()
+        })));
+        Ok(generated_operations)
+    }
+    fn to_string(&self) -> String { format!("{:?}", self) }
+}
+
+impl OperationWriteExecute for AckOperation {
+    fn to_bytes(&self) -> BoxedErrorResult<Vec<u8>> {
+        Ok(create_buf(&self, str_to_vec(Self::TAG)))
+    }
+    fn execute(&self, _source: Source) -> BoxedErrorResult<Vec<SendableOperation>> {
+        let mut generated_operations = apply_updates(&self.updates)?;
+        // Hearing from a member first hand is enough to stop suspecting it ourselves
+        if heartbeat::is_member(&self.id) {
+            heartbeat::observe_incarnation(&self.id, self.incarnation);
+            clear_suspicion(&self.id);
+            saw(&self.id);
+        }
+        if self.requester != *globals::MY_ID.read() {
+            // We were only helping with an indirect probe
+            generated_operations.push(SendableOperation::for_single(self.requester.clone(), Box::new(self.clone())));
+            return Ok(generated_operations)
+        }
+        if let Some(probe) = globals::DETECTOR_STATE.get_mut().probe.as_mut() {
+            if probe.seq == self.seq && probe.target == self.id {
+                probe.acked = true;
+            }
+        }
+        Ok(generated_operations)
+    }
+    fn to_string(&self) -> String { format!("{:?}", self) }
+}
@@ -1,47 +1,217 @@
 use async_std;
-use async_std::io::ReadExt;
 use async_std::stream::StreamExt;
 use async_std::task::spawn;
 use crate::{BoxedError, BoxedErrorResult};
 use crate::component_manager::*;
-use crate::constants;
-use crate::easyhash::{EasyHash, Hex};
+use crate::erasure::{self, ShardInfo};
 use crate::globals;
-use crate::heartbeat;
 use crate::operation::*;
+use crate::rpc::{self, RpcRequest};
+use crate::tls::{self, ClusterStream};
 use serde::{Serialize, Deserialize};
+use sha2::{Digest as _, Sha256};
 use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
 use std::fmt;
 use std::future::Future;
-use std::io::Write;
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
 
+// Types
+pub type Version = u64;
+pub type Digest = String;
+// Bumped by every rebalance of a file, with the coordinator's id breaking ties between concurrent ones
+pub type Placement = (u64, String);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileMetadata {
+    pub owners: HashSet<String>,
+    pub version: Version,
+    pub digests: HashMap<Version, Digest>,
+    pub placement: Placement,
+    // How many owners the file should have, 0 if it was never recorded and the configured default applies
+    pub replicas: u32,
+    // Set when this file is one shard of an erasure coded file
+    pub erasure: Option<ShardInfo>
+}
+
+// What survives a restart, kept in DATA_DIR next to the replicas it describes
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct NodeState {
+    pub id: Option<String>,
+    pub membership_list: Vec<String>,
+    pub all_file_owners: HashMap<String, FileMetadata>,
+    pub file_tombstones: HashMap<String, Version>
+}
+
+// What decides when a tombstone has done its job - local only, so a restart starts the wait over
+#[derive(Debug, Clone, Default)]
+pub struct TombstoneState {
+    // Owners of the deleted file that haven't confirmed dropping their replicas yet
+    pub unacked_owners: HashSet<String>,
+    // When the last of them confirmed, which file_tombstone_ttl counts from
+    pub acked_at: Option<Instant>
+}
+
+impl FileMetadata {
+    pub fn new() -> Self {
+        FileMetadata {
+            owners: HashSet::new(),
+            version: 0,
+            digests: HashMap::new(),
+            placement: (0, String::new()),
+            replicas: 0,
+            erasure: None
+        }
+    }
+
+    pub fn target_replicas(&self) -> u32 {
+        match (self.replicas, &self.erasure) {
+            (_, Some(_)) => 1,
+            (0, None) => globals::CONFIG.read().num_owners,
+            (replicas, None) => replicas
+        }
+    }
+
+    // The replication factor of the newest put wins, but an unknown one never overwrites a known one
+    pub fn merge_replicas(&mut self, replicas: u32, version: Version) {
+        if replicas > 0 && (self.replicas == 0 || version > self.version) {
+            self.replicas = replicas;
+        }
+    }
+}
+
+// The replication factor of a file, or the default one for a new file
+fn file_replicas(distributed_filename: &String) -> u32 {
+    match globals::ALL_FILE_OWNERS.read().get(distributed_filename) {
+        Some(metadata) => metadata.target_replicas(),
+        None => globals::CONFIG.read().num_owners
+    }
+}
+
+// args[0] = distributed filename, optionally suffixed with @version
+// args[1] = path to local file
 pub fn get(args: Vec<&str>) -> BoxedErrorResult<()> {
     check_joined()?;
     if args.len() != 2 {
-        return Err("Usage: get distributed_filename local_path".into())
+        return Err("Usage: get distributed_filename[@version] local_path".into())
     }
 
-    let distributed_filename = args[0].to_string();
+    let (distributed_filename, version) = parse_versioned_filename(args[0]);
     let local_path = args[1].to_string();
+    if let Some(info) = erasure::find_shard_info(&distributed_filename) {
+        let version = version.or(erasure::latest_version(&info)).ok_or("No versions found")?;
+        return erasure::get(&info, version, &local_path)
+    }
     
-    async_std::task::block_on(get_distributed_file_as_local(&distributed_filename, &local_path))?;
+    // Pin the newest version we know was committed, since an owner's newest local copy may be left over from a
+    // put that missed its quorum
+    let version = match version {
+        Some(version) => version,
+        None => globals::ALL_FILE_OWNERS.read()
+            .get(&distributed_filename)
+            .map(|metadata| metadata.version)
+            .ok_or(format!("No owners found for file {}", distributed_filename))?
+    };
+    let read_quorum = read_quorum(file_replicas(&distributed_filename));
+    async_std::task::block_on(get_distributed_file_as_local(&distributed_filename, Some(version), &local_path, read_quorum))?;
     Ok(())   
 }
 
+// args[0] = distributed filename
+// args[1] = number of versions
+// args[2] = path to local file
+pub fn get_versions(args: Vec<&str>) -> BoxedErrorResult<()> {
+    check_joined()?;
+    if args.len() != 3 {
+        return Err("Usage: get-versions distributed_filename num_versions local_path".into())
+    }
+
+    let distributed_filename = args[0].to_string();
+    let num_versions: Version = args[1].parse()?;
+    let local_path = args[2].to_string();
+    let shard_info = erasure::find_shard_info(&distributed_filename);
+    // Only committed versions have a digest, and versions can be missing in between - failed puts and deletes
+    // followed by a new put leave gaps
+    let mut versions: Vec<Version> = match (globals::ALL_FILE_OWNERS.read().get(&distributed_filename), &shard_info) {
+        (Some(metadata), _) => metadata.digests.keys().cloned().collect(),
+        (None, Some(info)) => erasure::committed_versions(info),
+        (None, None) => return Err(format!("No owners found for file {}", distributed_filename).into())
+    };
+    if num_versions == 0 {
+        return Err("Must request at least one version".into())
+    }
+    // Newest first, each one fetched separately and then stitched together
+    versions.sort_unstable_by(|a, b| b.cmp(a));
+    versions.truncate(num_versions as usize);
+    let read_quorum = read_quorum(file_replicas(&distributed_filename));
+    let mut output = std::fs::File::create(&local_path)?;
+    for version in versions {
+        let version_path = format!("{}.{}", local_path, version);
+        let result = (|| -> BoxedErrorResult<()> {
+            match &shard_info {
+                Some(info) => erasure::get(info, version, &version_path)?,
+                None => {
+                    async_std::task::block_on(fetch_distributed_file(&distributed_filename, Some(version), read_quorum, &version_path))?;
+                }
+            }
+            writeln!(output, "==> {}@{} <==", distributed_filename, version)?;
+            std::io::copy(&mut std::fs::File::open(&version_path)?, &mut output)?;
+            Ok(())
+        })();
+        let _ = std::fs::remove_file(&version_path);
+        // Don't leave a partial output behind
+        if let Err(e) = result {
+            let _ = std::fs::remove_file(&local_path);
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+// Optionally starts with --replicas N or --erasure K+M
 // args[0] = path to local file
 // args[1] = distributed filename
 pub fn put(args: Vec<&str>, sender: &OperationSender) -> BoxedErrorResult<()> {
     check_joined()?;
-    if args.len() != 2 {
-        return Err("Usage: put local_path distributed_filename".into())
+    let usage: BoxedErrorResult<()> = Err("Usage: put [--replicas N | --erasure K+M] local_path distributed_filename".into());
+    let (replicas, args) = match args.as_slice() {
+        ["--replicas", replicas, rest @ ..] => (Some(replicas.parse::<u32>()?), rest.to_vec()),
+        ["--erasure", scheme, rest @ ..] if rest.len() == 2 => {
+            let (data_shards, parity_shards) = erasure::parse_scheme(scheme)?;
+            return erasure::put(rest[0], rest[1], data_shards, parity_shards, sender)
+        },
+        _ => (None, args)
+    };
+    if args.len() != 2 || replicas == Some(0) {
+        return usage
+    }
+    check_distributed_filename(args[1])?;
+    if erasure::find_shard_info(args[1]).is_some() {
+        return Err(format!("{} is erasure coded, put it with --erasure", args[1]).into())
     }
     
     let local_path = args[0];
     let distributed_filename = args[1];
-    // Figure out who I am giving this file to
-    let dest_ids = gen_file_owners(&distributed_filename)?;
-    // Gossip who has the file now
+    let replicas = replicas.unwrap_or(file_replicas(&distributed_filename.to_string()));
+    // Figure out who I am giving this file to - existing files keep all of their versions together
+    // and the rebalancer adds or retires owners afterwards if the replication factor changed
+    let dest_ids: Vec<String> = match globals::ALL_FILE_OWNERS.read().get(distributed_filename) {
+        Some(metadata) if !metadata.owners.is_empty() => metadata.owners.iter().map(|x| x.to_string()).collect(),
+        _ => gen_file_owners(distributed_filename, replicas)?
+    };
+    let version = next_version(distributed_filename, &dest_ids)?;
+    let size = std::fs::metadata(local_path)?.len();
+    let digest = digest_of_file(&local_path.to_string())?;
+    // Send them the file
+    async_std::task::block_on(send_file_to_all(local_path.to_string(),
+                                               size,
+                                               digest.clone(),
+                                               distributed_filename.to_string(),
+                                               version,
+                                               &dest_ids,
+                                               write_quorum(std::cmp::min(replicas as usize, dest_ids.len()) as u32)))?;
+    // Only gossip who has the file once the write quorum stored it, so a failed put leaves no trace in the metadata
     sender.send(
         SendableOperation::for_successors(Box::new(NewFileOwnersOperation {
             distributed_filename: distributed_filename.to_string(),
@@ -49,13 +219,42 @@ pub fn put(args: Vec<&str>, sender: &OperationSender) -> BoxedErrorResult<()> {
                 .iter()
                 .map(|x| x.to_string())
                 .collect::<HashSet<_>>(),
-            from_failure: false
+            from_failure: false,
+            version,
+            digest: Some(digest),
+            replicas,
+            erasure: None
         }))
     )?;
-    // Send them the file
-    async_std::task::block_on(send_file_to_all(local_path.to_string(),
-                                               distributed_filename.to_string(),
-                                               &dest_ids))?;
+    Ok(())
+}
+
+pub fn delete(args: Vec<&str>, sender: &OperationSender) -> BoxedErrorResult<()> {
+    check_joined()?;
+    if args.len() != 1 {
+        return Err("Usage: delete distributed_filename".into())
+    }
+    check_distributed_filename(args[0])?;
+
+    // An erasure coded file goes away one shard at a time
+    let distributed_filenames = match erasure::find_shard_info(args[0]) {
+        Some(info) => erasure::shard_names(&info),
+        None => vec![args[0].to_string()]
+    };
+    for distributed_filename in distributed_filenames {
+        let version = match globals::ALL_FILE_OWNERS.read().get(&distributed_filename) {
+            Some(metadata) => metadata.version,
+            None => return Err(format!("No owners found for file {}", distributed_filename).into())
+        };
+        // Run it locally first so it gets gossiped from here
+        let delete_operation = DeleteFileOperation {
+            distributed_filename,
+            version
+        };
+        for generated_operation in delete_operation.execute(Source::myself())? {
+            sender.send(generated_operation)?;
+        }
+    }
     Ok(())
 }
 
@@ -71,13 +270,32 @@ pub fn ls(args: Vec<&str>) -> BoxedErrorResult<()> {
         1 => {
             // Just File
             let distributed_filename = args[0];
-            print_file_owners(Some(distributed_filename), false)?;
+            match erasure::find_shard_info(distributed_filename) {
+                Some(info) if !globals::ALL_FILE_OWNERS.read().contains_key(distributed_filename) => erasure::print(&info)?,
+                _ => print_file_owners(Some(distributed_filename), false)?
+            }
             Ok(())
         },
         _ => invalid_args
     }
 }
 
+// Asks a member, this one by default, which replicas it actually has on disk
+pub fn ls_node(args: Vec<&str>) -> BoxedErrorResult<()> {
+    check_joined()?;
+    let member_id = match args.as_slice() {
+        [] => globals::MY_ID.read().clone(),
+        [member_id] => member_id.to_string(),
+        _ => return Err("Usage: ls-node [member_id]".into())
+    };
+    let stored_replicas = rpc::call(&member_id, &StoredReplicasRequest {})?;
+    println!("{} stores {} replicas", member_id, stored_replicas.len());
+    for replica in stored_replicas.iter() {
+        println!("{}@{} {} bytes", replica.distributed_filename, replica.version, replica.size);
+    }
+    Ok(())
+}
+
 // TODO: You wrote this very late - maybe fix
 fn print_file_owners(maybe_distributed_filename: Option<&str>, full: bool) -> BoxedErrorResult<()> {
     let all_file_owners = globals::ALL_FILE_OWNERS.read();
@@ -88,8 +306,8 @@ fn print_file_owners(maybe_distributed_filename: Option<&str>, full: bool) -> Bo
         (Some(distributed_filename), false) => {
             // Print the files owners
             match all_file_owners.get(distributed_filename) {
-                Some(owners) => {
-                    println!("{:?}", owners);
+                Some(metadata) => {
+                    println!("{:?}", metadata);
                 },
                 None => {
                     // A little unoptimal - change if above format changes
@@ -109,142 +327,805 @@ fn print_file_owners(maybe_distributed_filename: Option<&str>, full: bool) -> Bo
     }
 }
 
+// Fetches every version of the file into the data directory
+// Any single surviving copy is good enough here since this is used to recover from failures
+// Fetching can take a while, so it is kept off the thread that executes UDP operations and answers pings
+fn spawn_get_distributed_file(distributed_filename: String) {
+    std::thread::spawn(move || {
+        if let Err(e) = async_std::task::block_on(get_distributed_file(&distributed_filename)) {
+            let _ = log(format!("Could not fetch {}: {}", distributed_filename, e));
+        }
+    });
+}
+
 async fn get_distributed_file(distributed_filename: &String) -> BoxedErrorResult<()> {
-    get_distributed_file_as_local(distributed_filename, &distributed_file_path(distributed_filename)).await
+    let latest_version = match globals::ALL_FILE_OWNERS.read().get(distributed_filename) {
+        Some(metadata) => metadata.version,
+        None => return Err(format!("No owners found for file {}", distributed_filename).into())
+    };
+    let held_versions = local_versions(distributed_filename)?;
+    for version in 1..=latest_version {
+        let local_path = distributed_file_path(distributed_filename, version);
+        // Replicas kept from before a restart only need the versions written while we were gone
+        if held_versions.contains(&version) && std::path::Path::new(&digest_path(&local_path)).exists() {
+            continue;
+        }
+        match fetch_distributed_file(distributed_filename, Some(version), 1, &local_path).await {
+            Ok(file) => {
+                std::fs::write(digest_path(&local_path), &file.digest)?;
+            },
+            Err(e) => {
+                log(format!("Could not fetch {}@{}: {}", distributed_filename, version, e))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn get_distributed_file_as_local(distributed_filename: &String, version: Option<Version>, local_path: &String, read_quorum: usize) -> BoxedErrorResult<()> {
+    fetch_distributed_file(distributed_filename, version, read_quorum, local_path).await?;
+    Ok(())
 }
 
-async fn get_distributed_file_as_local(distributed_filename: &String, local_path: &String) -> BoxedErrorResult<()> {
-    // TODO: Find owners
+// Streams the newest verified copy of the file out of the first read_quorum owners that answered into local_path
+pub async fn fetch_distributed_file(distributed_filename: &String, version: Option<Version>, read_quorum: usize, local_path: &String) -> BoxedErrorResult<SendFileOperation> {
     let operation = SendableOperation::for_owners(&distributed_filename, Box::new(GetOperation {
         distributed_filename: distributed_filename.clone(),
-        local_path: local_path.clone()
+        version
     }));
 
     let mut streams = operation
         .write_all_tcp_async()
         .await?;
 
-    // TODO: Redo whatever tf going on here
-    match streams.len() {
-        0 => Err(format!("No owners found for file {}", distributed_filename).into()),
-        _ => {
-            let (result, source) = streams[0]
-                .try_read_operation()
-                .await?;
-            result.execute(source)?;
-            Ok(())       
+    if streams.is_empty() {
+        return Err(format!("No reachable owners found for file {}", distributed_filename).into())
+    }
+
+    // Gather replies until the quorum is met, keeping the newest version seen
+    // Every replica goes to its own part file so that only the winner is kept around
+    let mut num_replies = 0;
+    let mut newest_reply: Option<(SendFileOperation, String)> = None;
+    for (idx, stream) in streams.iter_mut().enumerate() {
+        if num_replies >= read_quorum {
+            break;
+        }
+        let part_path = format!("{}.part{}", local_path, idx);
+        match receive_replica(stream, &part_path).await {
+            Ok(reply) => {
+                num_replies += 1;
+                if newest_reply.as_ref().is_none_or(|(newest, _)| reply.version > newest.version) {
+                    if let Some((_, old_part_path)) = newest_reply.replace((reply, part_path)) {
+                        std::fs::remove_file(old_part_path)?;
+                    }
+                } else {
+                    std::fs::remove_file(part_path)?;
+                }
+            },
+            Err(e) => {
+                // Don't count it and move on to the next owner
+                let _ = std::fs::remove_file(&part_path);
+                let msg = format!("Bad replica of {} on {:?}: {}", distributed_filename, stream.peer_addr(), e);
+                println!("{}", msg);
+                log(msg)?;
+            }
         }
     }
+    match newest_reply {
+        Some((reply, part_path)) if num_replies >= read_quorum => {
+            std::fs::rename(part_path, local_path)?;
+            Ok(reply)
+        },
+        Some((_, part_path)) => {
+            std::fs::remove_file(part_path)?;
+            Err(format!("Read quorum not reached for {}: {}/{} replies", distributed_filename, num_replies, read_quorum).into())
+        },
+        None => Err(format!("Read quorum not reached for {}: {}/{} replies", distributed_filename, num_replies, read_quorum).into())
+    }
 }
 
-async fn read_file_to_buf(local_path: &String) -> BoxedErrorResult<Vec<u8>> {
-    let mut data_buf: Vec<u8> = Vec::new();
-    let mut file = async_std::fs::File::open(&local_path).await?;
-    file.read_to_end(&mut data_buf).await?;
-    Ok(data_buf)
+// Reads a single owner's reply to a GetOperation and checks what it sent against the recorded digest
+async fn receive_replica(stream: &mut ClusterStream, part_path: &String) -> BoxedErrorResult<SendFileOperation> {
+    let mut reply = await_reply::<SendFileOperation>(stream).await?;
+    let actual_digest = receive_file_body(stream, reply.size, part_path).await?;
+    // Without a digest recorded at put time there is nothing the replica can be checked against
+    let recorded_digest = recorded_digest(&reply.filename, reply.version)
+        .ok_or(format!("No digest recorded for {}@{} to check the replica against", reply.filename, reply.version))?;
+    if actual_digest != recorded_digest {
+        report_bad_replica(stream, &reply, &actual_digest).await;
+        return Err(format!("Checksum mismatch, expected {} but got {}", recorded_digest, actual_digest).into())
+    }
+    reply.digest = actual_digest;
+    Ok(reply)
 }
 
-async fn send_file_to_all(local_path: String, distributed_filename: String, dest_ids: &Vec<String>) ->
-BoxedErrorResult<()> {
-    let data_buf = read_file_to_buf(&local_path).await?;
-    let operation = SendableOperation::for_id_list(dest_ids.clone(), Box::new(SendFileOperation {
-        filename: distributed_filename,
-        data: data_buf,
+// Lets the owner know its copy is corrupt so that it replaces it rather than keep handing it out
+async fn report_bad_replica(stream: &ClusterStream, file: &SendFileOperation, actual_digest: &Digest) {
+    let peer_addr = match stream.peer_addr() {
+        Ok(peer_addr) => peer_addr.to_string(),
+        Err(_) => return
+    };
+    let operation = SendableOperation {
+        dests: Destinations::TCPAddr(vec![peer_addr.clone()]),
+        operation: Box::new(BadReplicaOperation {
+            distributed_filename: file.filename.clone(),
+            version: file.version,
+            digest: actual_digest.clone()
+        })
+    };
+    if let Err(e) = operation.write_all_tcp_async().await {
+        let _ = log(format!("Could not report the bad replica of {}@{} to {}: {}", file.filename, file.version, peer_addr, e));
+    }
+}
+
+pub async fn send_file_to_all(local_path: String, size: u64, digest: Digest, distributed_filename: String, version: Version, dest_ids: &[String],
+                          write_quorum: usize) -> BoxedErrorResult<()> {
+    let operation = SendableOperation::for_id_list(dest_ids.to_owned(), Box::new(SendFileOperation {
+        filename: distributed_filename.clone(),
+        version,
+        digest,
+        size,
         is_distributed: true
     }));
-    operation.write_all_tcp_async().await?;
+    let mut streams = operation.write_all_tcp_async().await?;
+    send_file_body(&mut streams, &local_path).await?;
+
+    // Only call it a success once enough owners have confirmed they stored it
+    let mut acked_addrs: Vec<String> = Vec::new();
+    for stream in streams.iter_mut() {
+        match await_reply::<WriteAckOperation>(stream).await {
+            Ok(ack) => match ack.error {
+                None => acked_addrs.extend(stream.peer_addr().map(|addr| addr.to_string())),
+                Some(e) => log(format!("{:?} failed to store {}@{}: {}", stream.peer_addr(), ack.filename, ack.version, e))?
+            },
+            Err(e) => log(format!("No write ack from {:?}: {}", stream.peer_addr(), e))?
+        }
+    }
+    let num_acks = acked_addrs.len();
+    if num_acks < write_quorum {
+        // Nothing gets gossiped about a failed put, so the owners that stored it have to be told to drop it
+        let operation = SendableOperation {
+            dests: Destinations::TCPAddr(acked_addrs),
+            operation: Box::new(DiscardReplicaOperation {
+                distributed_filename: distributed_filename.clone(),
+                version
+            })
+        };
+        if let Err(e) = operation.write_all_tcp_async().await {
+            log(format!("Could not discard the partial put of {}@{}: {}", distributed_filename, version, e))?;
+        }
+        return Err(format!("Write quorum not reached for {}@{}: {}/{} acks", distributed_filename, version, num_acks, write_quorum).into())
+    }
+    log(format!("Stored {}@{} on {}/{} owners", distributed_filename, version, num_acks, dest_ids.len()))?;
+    Ok(())
+}
+
+// Writes the file out as chunks on every stream, dropping any stream that stops accepting them
+// The file is read a chunk at a time so memory stays bounded no matter how big it is
+async fn send_file_body(streams: &mut Vec<ClusterStream>, local_path: &String) -> BoxedErrorResult<()> {
+    let mut file = std::fs::File::open(local_path)?;
+    let mut buf: Vec<u8> = vec![0; globals::CONFIG.read().chunk_size];
+    loop {
+        let num_read = file.read(&mut buf)?;
+        if num_read == 0 {
+            break;
+        }
+        let chunk = FileChunkOperation {
+            data: buf[..num_read].to_vec()
+        }.to_bytes()?;
+        let mut idx = 0;
+        while idx < streams.len() {
+            match streams[idx].write_frame(&chunk).await {
+                Ok(_) => idx += 1,
+                Err(e) => {
+                    log(format!("Dropping {:?} mid-transfer of {}: {}", streams[idx].peer_addr(), local_path, e))?;
+                    streams.remove(idx);
+                }
+            }
+        }
+    }
     Ok(())
 }
 
-pub async fn file_server<'a>(_sender: &'a OperationSender) -> BoxedErrorResult<()> {
+// Reads size bytes worth of chunks off the stream into local_path and returns their digest
+async fn receive_file_body(stream: &mut ClusterStream, size: u64, local_path: &String) -> BoxedErrorResult<Digest> {
+    let mut file = std::fs::File::create(local_path)?;
+    let mut hasher = Sha256::new();
+    let mut num_received: u64 = 0;
+    while num_received < size {
+        let chunk = await_reply::<FileChunkOperation>(stream).await?;
+        if chunk.data.is_empty() {
+            return Err(format!("Received an empty chunk after {}/{} bytes", num_received, size).into())
+        }
+        num_received += chunk.data.len() as u64;
+        if num_received > size {
+            return Err(format!("Received more than the expected {} bytes", size).into())
+        }
+        hasher.update(&chunk.data);
+        file.write_all(&chunk.data)?;
+    }
+    Ok(finish_digest(hasher))
+}
+
+async fn await_reply<T>(stream: &mut ClusterStream) -> BoxedErrorResult<T>
+where T: TaggedOperation {
+    let timeout = Duration::from_millis(globals::CONFIG.read().quorum_timeout);
+    async_std::future::timeout(timeout, read_typed_operation(stream)).await?
+}
+
+pub fn rebalancer(sender: &OperationSender) -> ComponentResult {
+    if !is_joined() {
+        return Ok(())
+    }
+    expire_file_tombstones()?;
+    // Start over whenever the membership grows again since the plan is stale by then
+    if *globals::REBALANCE_NEEDED.read() {
+        globals::REBALANCE_NEEDED.write(false);
+        let misplaced_files = find_misplaced_files()?;
+        if !misplaced_files.is_empty() {
+            log(format!("Rebalancing {} misplaced files", misplaced_files.len()))?;
+        }
+        *globals::REBALANCE_QUEUE.get_mut() = misplaced_files.into_iter().collect();
+    }
+    // Throttled so that a big move doesn't starve everything else of bandwidth
+    let batch_size = globals::CONFIG.read().rebalance_batch_size;
+    for _ in 0..batch_size {
+        let distributed_filename = match globals::REBALANCE_QUEUE.get_mut().pop_front() {
+            Some(distributed_filename) => distributed_filename,
+            None => return Ok(())
+        };
+        match rebalance_file(&distributed_filename, sender) {
+            Ok(()) => log(format!("Rebalanced {}, {} files left", distributed_filename, globals::REBALANCE_QUEUE.read().len()))?,
+            Err(e) => log(format!("Could not rebalance {}: {}", distributed_filename, e))?
+        }
+        if globals::REBALANCE_QUEUE.read().is_empty() {
+            log("Finished rebalancing".to_string())?;
+        }
+    }
+    Ok(())
+}
+
+pub async fn file_server(sender: &OperationSender) -> BoxedErrorResult<()> {
     let server = globals::SERVER_SOCKET.read();
     let mut incoming = server.incoming();
 
     while let Some(stream) = incoming.next().await {
         let connection = stream?;
         log(format!("Handling connection from {:?}", connection.peer_addr()));
-        spawn(handle_connection(connection));
+        let sender = sender.clone();
+        spawn(async move {
+            if let Err(e) = handle_connection(connection, &sender).await {
+                let _ = log(e.to_string());
+            }
+        });
     }
     Ok(())
 }
 
-async fn handle_connection(mut connection: async_std::net::TcpStream) -> BoxedErrorResult<()> {
+async fn handle_connection(connection: async_std::net::TcpStream, sender: &OperationSender) -> BoxedErrorResult<()> {
+    let mut connection = tls::accept(connection).await?;
     let (operation, source) = connection.try_read_operation().await?;
-    // TODO: Think about what standard we want with these
-    let _generated_operations = operation.execute(source)?;
+    // Operations too big for UDP arrive here instead, and whatever they generate goes out the same way as from the receiver
+    for generated_operation in operation.execute(source)? {
+        sender.send(generated_operation)?;
+    }
+    Ok(())
+}
+
+pub fn schedule_rebalance() {
+    globals::REBALANCE_NEEDED.write(true);
+}
+
+// The files this node is responsible for moving, i.e. whose owners no longer match the ring
+fn find_misplaced_files() -> BoxedErrorResult<Vec<String>> {
+    let mut misplaced_files = Vec::new();
+    for (distributed_filename, metadata) in globals::ALL_FILE_OWNERS.read().iter() {
+        if is_responsible_owner(&metadata.owners) && !is_placed_correctly(distributed_filename, metadata)? {
+            misplaced_files.push(distributed_filename.clone());
+        }
+    }
+    misplaced_files.sort();
+    Ok(misplaced_files)
+}
+
+fn is_placed_correctly(distributed_filename: &str, metadata: &FileMetadata) -> BoxedErrorResult<bool> {
+    Ok(gen_desired_owners(distributed_filename, metadata)? == metadata.owners)
+}
+
+// Exactly one current owner moves or re-replicates each file - the lowest one that is still alive
+fn is_responsible_owner(owners: &HashSet<String>) -> bool {
+    let membership_list = globals::MEMBERSHIP_LIST.read();
+    let coordinator = owners
+        .iter()
+        .filter(|x| membership_list.binary_search(x).is_ok())
+        .min();
+    coordinator == Some(&*globals::MY_ID.read())
+}
+
+// Copies every version to the new owners first and only then hands the file over to them, so the
+// file never has fewer replicas than it started with
+fn rebalance_file(distributed_filename: &String, sender: &OperationSender) -> BoxedErrorResult<()> {
+    let metadata = match globals::ALL_FILE_OWNERS.read().get(distributed_filename) {
+        Some(metadata) => metadata.clone(),
+        None => return Ok(())
+    };
+    if !is_responsible_owner(&metadata.owners) || is_placed_correctly(distributed_filename, &metadata)? {
+        return Ok(())
+    }
+    let desired_owners = gen_desired_owners(distributed_filename, &metadata)?;
+    let added_owners: Vec<String> = desired_owners.difference(&metadata.owners).cloned().collect();
+    let retired_owners: HashSet<String> = metadata.owners.difference(&desired_owners).cloned().collect();
+    let versions: Vec<Version> = local_versions(distributed_filename)?
+        .into_iter()
+        .filter(|version| *version <= metadata.version)
+        .collect();
+    if versions.last() != Some(&metadata.version) {
+        return Err(format!("Missing {}@{} locally", distributed_filename, metadata.version).into())
+    }
+    if !added_owners.is_empty() {
+        for version in versions.iter() {
+            let local_path = distributed_file_path(distributed_filename, *version);
+            let size = std::fs::metadata(&local_path)?.len();
+            let digest = match std::fs::read_to_string(digest_path(&local_path)) {
+                Ok(digest) => digest.trim().to_string(),
+                Err(_) => digest_of_file(&local_path)?
+            };
+            async_std::task::block_on(send_file_to_all(local_path,
+                                                       size,
+                                                       digest,
+                                                       distributed_filename.clone(),
+                                                       *version,
+                                                       &added_owners,
+                                                       added_owners.len()))?;
+        }
+    }
+    // Replacing the whole owner set in one versioned step keeps concurrent rebalances from undoing each other
+    let move_replicas_operation = MoveReplicasOperation {
+        distributed_filename: distributed_filename.clone(),
+        owners: desired_owners,
+        placement: (metadata.placement.0 + 1, globals::MY_ID.read().clone()),
+        version: metadata.version
+    };
+    for generated_operation in move_replicas_operation.execute(Source::myself())? {
+        sender.send(generated_operation)?;
+    }
+    log(format!("Moved {} from {:?} to {:?}", distributed_filename, retired_owners, added_owners))?;
     Ok(())
 }
 
 // Helpers
-fn gen_file_owners(filename: &str) -> BoxedErrorResult<Vec<String>> {
-    let file_idx = filename.easyhash();
-    heartbeat::gen_neighbor_list_from(file_idx as i32, 1, constants::NUM_OWNERS, true)
-}
-
-// TODO: This function makes the entire system assume there are always at least two nodes in the system
-//       and the file must have an owner or else the operation will not work correctly. This is fine for now
-//       but it is worth improving sooner rather than later (make distinct Error types to differentiate, etc).
-fn gen_new_file_owner(filename: &str) -> BoxedErrorResult<String> {
-    match globals::ALL_FILE_OWNERS.read().get(filename) {
-        Some(owners) => {
-            let potential_owners = gen_file_owners(filename)?;
-            for potential_owner in &potential_owners {
-                if !owners.contains(potential_owner) {
-                    return Ok(potential_owner.clone());
+fn gen_file_owners(filename: &str, replicas: u32) -> BoxedErrorResult<Vec<String>> {
+    let owners = globals::HASH_RING.read().owners(filename, replicas as usize);
+    if owners.is_empty() {
+        return Err("Cannot place a file because the membership list is empty".into())
+    }
+    Ok(owners)
+}
+
+// Where the ring wants the file, shards being placed relative to the file they were split from
+fn gen_desired_owners(filename: &str, metadata: &FileMetadata) -> BoxedErrorResult<HashSet<String>> {
+    let desired_owners = match &metadata.erasure {
+        Some(info) => erasure::desired_shard_owner(info)?,
+        None => gen_file_owners(filename, metadata.target_replicas())?
+    };
+    Ok(desired_owners.into_iter().collect())
+}
+
+// As many owners as the file is missing, taken in ring order from the ones it doesn't already have
+fn gen_new_file_owners(filename: &str, metadata: &FileMetadata) -> BoxedErrorResult<Vec<String>> {
+    if let Some(info) = &metadata.erasure {
+        return Ok(erasure::gen_new_shard_owner(info))
+    }
+    let owners = &metadata.owners;
+    let num_missing = (metadata.target_replicas() as usize).saturating_sub(owners.len());
+    Ok(gen_file_owners(filename, metadata.target_replicas())?
+        .into_iter()
+        .filter(|x| !owners.contains(x))
+        .take(num_missing)
+        .collect())
+}
+
+fn majority(num_owners: u32) -> usize {
+    (num_owners / 2 + 1) as usize
+}
+
+// Configured quorums are capped at the file's own replication factor
+fn write_quorum(replicas: u32) -> usize {
+    match globals::CONFIG.read().write_quorum {
+        Some(quorum) => std::cmp::min(quorum, replicas) as usize,
+        None => majority(replicas)
+    }
+}
+
+fn read_quorum(replicas: u32) -> usize {
+    match globals::CONFIG.read().read_quorum {
+        Some(quorum) => std::cmp::min(quorum, replicas) as usize,
+        None => majority(replicas)
+    }
+}
+
+pub fn distributed_file_path(filename: &String, version: Version) -> String {
+    format!("{}/{}@{}", globals::CONFIG.read().data_dir, filename, version)
+}
+
+// Where the digest of a stored replica is kept next to it
+pub fn digest_path(path: &String) -> String {
+    format!("{}.sha256", path)
+}
+
+pub fn digest_of_file(path: &String) -> BoxedErrorResult<Digest> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(finish_digest(hasher))
+}
+
+fn finish_digest(hasher: Sha256) -> Digest {
+    hasher.finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// The digest the cluster recorded for the version at put time
+fn recorded_digest(distributed_filename: &String, version: Version) -> Option<Digest> {
+    globals::ALL_FILE_OWNERS.read()
+        .get(distributed_filename)
+        .and_then(|metadata| metadata.digests.get(&version).cloned())
+}
+
+// Shards are stored as name#index and versions are asked for as name@version, so users can't name a file like either
+pub fn check_distributed_filename(distributed_filename: &str) -> BoxedErrorResult<()> {
+    match distributed_filename.chars().find(|c| *c == '#' || *c == '@') {
+        Some('#') => Err(format!("Invalid distributed filename {:?}: '#' is reserved for erasure coded shards", distributed_filename).into()),
+        Some(_) => Err(format!("Invalid distributed filename {:?}: '@' is reserved for versions", distributed_filename).into()),
+        None => Ok(())
+    }
+}
+
+// Splits name@version, treating anything that doesn't parse as part of the name
+pub fn parse_versioned_filename(versioned_filename: &str) -> (String, Option<Version>) {
+    if let Some(idx) = versioned_filename.rfind('@') {
+        if let Ok(version) = versioned_filename[idx+1..].parse::<Version>() {
+            return (versioned_filename[..idx].to_string(), Some(version));
+        }
+    }
+    (versioned_filename.to_string(), None)
+}
+
+// All versions of the file stored on this node, oldest first
+pub fn local_versions(filename: &String) -> BoxedErrorResult<Vec<Version>> {
+    let prefix = format!("{}@", filename);
+    let mut versions = Vec::new();
+    for entry in std::fs::read_dir(&globals::CONFIG.read().data_dir)? {
+        let entry_name = entry?.file_name().to_string_lossy().to_string();
+        if entry_name.starts_with(&prefix) {
+            if let Ok(version) = entry_name[prefix.len()..].parse::<Version>() {
+                versions.push(version);
+            }
+        }
+    }
+    versions.sort();
+    Ok(versions)
+}
+
+// Every distributed file with at least one version stored on this node
+fn all_local_versions() -> BoxedErrorResult<HashMap<String, Vec<Version>>> {
+    let mut all_versions: HashMap<String, Vec<Version>> = HashMap::new();
+    for entry in std::fs::read_dir(&globals::CONFIG.read().data_dir)? {
+        let entry_name = entry?.file_name().to_string_lossy().to_string();
+        if let (filename, Some(version)) = parse_versioned_filename(&entry_name) {
+            all_versions.entry(filename).or_default().push(version);
+        }
+    }
+    for versions in all_versions.values_mut() {
+        versions.sort();
+    }
+    Ok(all_versions)
+}
+
+// Removes the replicas of every version up to and including up_to, stale copies included
+fn remove_local_versions(distributed_filename: &String, up_to: Version) -> BoxedErrorResult<()> {
+    for version in local_versions(distributed_filename)? {
+        if version <= up_to {
+            let local_path = distributed_file_path(distributed_filename, version);
+            std::fs::remove_file(&local_path)?;
+            if let Err(e) = std::fs::remove_file(digest_path(&local_path)) {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    return Err(e.into());
                 }
             }
-            Err(format!("No new owners available for file {}", filename).into())
+            log(format!("Deleted local replica {}", local_path))?;
+        }
+    }
+    Ok(())
+}
+
+fn expire_file_tombstones() -> BoxedErrorResult<()> {
+    let ttl = Duration::from_millis(globals::CONFIG.read().file_tombstone_ttl);
+    let members: HashSet<String> = globals::MEMBERSHIP_LIST.read().iter().cloned().collect();
+    let expired = remove_expired_tombstones(&mut globals::FILE_TOMBSTONES.get_mut(),
+                                            &mut globals::TOMBSTONE_STATES.get_mut(),
+                                            &members,
+                                            ttl);
+    for filename in expired {
+        log(format!("Expired the tombstone of {}", filename))?;
+    }
+    Ok(())
+}
+
+// Tombstones are dropped ttl after every owner acked the delete, by when no gossip about the deleted version
+// should be left in flight. Owners that failed or left since don't hold anything up. Returns the files whose
+// tombstones were dropped.
+pub fn remove_expired_tombstones(file_tombstones: &mut HashMap<String, Version>,
+                                 tombstone_states: &mut HashMap<String, TombstoneState>,
+                                 members: &HashSet<String>,
+                                 ttl: Duration) -> Vec<String> {
+    tombstone_states.retain(|filename, _| file_tombstones.contains_key(filename));
+    let mut expired = Vec::new();
+    for filename in file_tombstones.keys() {
+        // Tombstones we merged or reloaded come without any owners to wait for
+        let state = tombstone_states.entry(filename.clone()).or_default();
+        state.unacked_owners.retain(|owner| members.contains(owner));
+        if !state.unacked_owners.is_empty() {
+            continue;
+        }
+        if state.acked_at.get_or_insert_with(Instant::now).elapsed() >= ttl {
+            expired.push(filename.clone());
+        }
+    }
+    for filename in expired.iter() {
+        file_tombstones.remove(filename);
+        tombstone_states.remove(filename);
+    }
+    expired
+}
+
+fn node_state_path() -> String {
+    format!("{}/.node_state", globals::CONFIG.read().data_dir)
+}
+
+pub fn load_node_state() -> BoxedErrorResult<()> {
+    // An unreadable state only costs us the metadata - the replicas are still reclaimed on join
+    let node_state: NodeState = match std::fs::read(node_state_path()) {
+        Ok(bytes) => bincode::deserialize(&bytes).unwrap_or_else(|e| {
+            let _ = log(format!("Ignoring unreadable {}: {}", node_state_path(), e));
+            NodeState::default()
+        }),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => NodeState::default(),
+        Err(e) => return Err(e.into())
+    };
+    log(format!("Loaded metadata for {} files from a previous run", node_state.all_file_owners.len()))?;
+    *globals::ALL_FILE_OWNERS.get_mut() = node_state.all_file_owners.clone();
+    *globals::FILE_TOMBSTONES.get_mut() = node_state.file_tombstones.clone();
+    globals::PREVIOUS_STATE.write(node_state);
+    Ok(())
+}
+
+// Written to a temp file first so a crash mid-write never leaves a truncated state behind
+pub fn persist_node_state() -> BoxedErrorResult<()> {
+    let node_state = NodeState {
+        id: match is_joined() {
+            true  => Some(globals::MY_ID.read().clone()),
+            false => globals::PREVIOUS_STATE.read().id.clone()
+        },
+        membership_list: match is_joined() {
+            true  => globals::MEMBERSHIP_LIST.read().clone(),
+            false => globals::PREVIOUS_STATE.read().membership_list.clone()
         },
-        None => Err(format!("No owner found for file {}", filename).into())
+        all_file_owners: globals::ALL_FILE_OWNERS.read().clone(),
+        file_tombstones: globals::FILE_TOMBSTONES.read().clone()
+    };
+    let bytes = bincode::serialize(&node_state)?;
+    let path = node_state_path();
+    if let Ok(persisted_bytes) = std::fs::read(&path) {
+        if persisted_bytes == bytes {
+            return Ok(());
+        }
     }
+    let temp_path = format!("{}.tmp", path);
+    std::fs::write(&temp_path, &bytes)?;
+    std::fs::rename(&temp_path, &path)?;
+    Ok(())
 }
 
-fn distributed_file_path(filename: &String) -> String {
-    format!("{}/{}", constants::DATA_DIR, filename)
+// Claims the replicas left in DATA_DIR by a previous run under our current id so the cluster doesn't treat them as lost
+// Deleted ones are dropped and stale ones are caught up the same way a newly assigned owner would be
+pub fn announce_local_replicas() -> BoxedErrorResult<Vec<SendableOperation>> {
+    let mut generated_operations: Vec<SendableOperation> = Vec::new();
+    let my_id = globals::MY_ID.read().clone();
+    for (distributed_filename, versions) in all_local_versions()? {
+        let latest_version = match versions.last() {
+            Some(version) => *version,
+            None => continue
+        };
+        let deleted_version = globals::FILE_TOMBSTONES.read().get(&distributed_filename).cloned();
+        if let Some(deleted_version) = deleted_version {
+            remove_local_versions(&distributed_filename, deleted_version)?;
+            if latest_version <= deleted_version {
+                continue;
+            }
+        }
+        let digest = std::fs::read_to_string(digest_path(&distributed_file_path(&distributed_filename, latest_version))).ok();
+        let new_owner_operation = NewFileOwnersOperation {
+            distributed_filename: distributed_filename.clone(),
+            new_owners: vec![my_id.clone()].into_iter().collect(),
+            from_failure: true,
+            version: latest_version,
+            digest,
+            replicas: 0,
+            erasure: None
+        };
+        log(format!("Announcing local replica {}@{}", distributed_filename, latest_version))?;
+        generated_operations.append(&mut new_owner_operation.execute(Source::myself())?);
+    }
+    Ok(generated_operations)
 }
 
 // Returns messages to be gossiped
+// Every survivor runs this, but only the lowest surviving owner of each file acts on it. Any owner missing
+// from the membership list counts as lost, so files are still recovered when the responsible owner died too.
 // TODO: This function does NOT scale as # of files gets very large
 pub fn handle_failed_node(failed_id: &String) -> BoxedErrorResult<Vec<SendableOperation>> {
-    match heartbeat::is_master() {
-        true => {
-            let mut generated_operations: Vec<SendableOperation> = Vec::new();
-            let myself_source = Source::myself();
-            // Find files they owned
-            let mut lost_files: HashSet<String> = HashSet::new();
-            for (distributed_filename, owners) in globals::ALL_FILE_OWNERS.read().iter() {
-                if owners.contains(failed_id) {
-                    lost_files.insert(distributed_filename.clone());
-                }
+    let mut generated_operations: Vec<SendableOperation> = Vec::new();
+    let myself_source = Source::myself();
+    // Find the files I am now responsible for and who they lost
+    let mut lost_files_by_owner: HashMap<String, HashSet<String>> = HashMap::new();
+    let mut lost_files: Vec<String> = Vec::new();
+    let membership_list = globals::MEMBERSHIP_LIST.read().clone();
+    let all_file_owners = globals::ALL_FILE_OWNERS.read();
+    for (distributed_filename, metadata) in all_file_owners.iter() {
+        let dead_owners: Vec<&String> = metadata.owners
+            .iter()
+            .filter(|x| membership_list.binary_search(x).is_err())
+            .collect();
+        if dead_owners.is_empty() || !is_responsible_owner(&recovery_owners(metadata, &all_file_owners)) {
+            continue;
+        }
+        for dead_owner in dead_owners {
+            lost_files_by_owner.entry(dead_owner.clone()).or_default().insert(distributed_filename.clone());
+        }
+        lost_files.push(distributed_filename.clone());
+    }
+    drop(all_file_owners);
+    log(format!("Responsible for {} files lost with {}", lost_files.len(), failed_id))?;
+    // Send that they no longer own those files
+    for (failed_owner, lost_files) in lost_files_by_owner.drain() {
+        let lost_file_operation = LostFilesOperation {
+            failed_owner,
+            lost_files
+        };
+        generated_operations.append(&mut lost_file_operation.execute(myself_source.clone())?);
+    }
+    // Gen new owners of the file and propagate
+    for lost_file in &lost_files {
+        let metadata = match globals::ALL_FILE_OWNERS.read().get(lost_file) {
+            Some(metadata) => metadata.clone(),
+            None => continue
+        };
+        let new_owners = match gen_new_file_owners(lost_file, &metadata) {
+            Ok(new_owners) if !new_owners.is_empty() => new_owners,
+            Ok(_) => {
+                log(format!("No new owners available for file {}", lost_file))?;
+                continue;
+            },
+            Err(e) => {
+                log(format!("Could not pick new owners for file {}: {}", lost_file, e))?;
+                continue;
             }
-            log("Found lost files".to_string());
-            // Send that they no longer own those files
-            // Separate operation so that this acts like a confirmation to fully forget about the node from
-            // the master. Can be used with a delay later if you want more error resistance.
-            let lost_file_operation = LostFilesOperation {
-                failed_owner: failed_id.clone(),
-                lost_files: lost_files.clone()
-            };
-            generated_operations.append(&mut lost_file_operation.execute(myself_source.clone())?);
-            log("Executed lost files operation locally".to_string());
-            // Gen new owners of the file and propagate
-            let mut new_owners: HashMap<String, HashSet<String>> = HashMap::new();
-            for lost_file in &lost_files {
-                let new_owner = gen_new_file_owner(&lost_file)?;
-                // TODO: Maybe optimize this into one fat packet - probably a new operation?
-                let new_owner_operation = NewFileOwnersOperation {
-                    distributed_filename: lost_file.clone(),
-                    new_owners: vec![new_owner].iter().map(|x| x.to_string()).collect(),
-                    from_failure: true
-                };
-                generated_operations.append(&mut new_owner_operation.execute(myself_source.clone())?);
+        };
+        // TODO: Maybe optimize this into one fat packet - probably a new operation?
+        // Carrying the current version lets a concurrent delete of it still win
+        let new_owner_operation = NewFileOwnersOperation {
+            distributed_filename: lost_file.clone(),
+            new_owners: new_owners.into_iter().collect(),
+            from_failure: true,
+            version: metadata.version,
+            digest: None,
+            replicas: metadata.replicas,
+            erasure: metadata.erasure.clone()
+        };
+        generated_operations.append(&mut new_owner_operation.execute(myself_source.clone())?);
+    }
+    Ok(generated_operations)
+}
+
+// A shard has a single owner that may well be the one that died, so the owners of its sibling shards look after it too
+fn recovery_owners(metadata: &FileMetadata, all_file_owners: &HashMap<String, FileMetadata>) -> HashSet<String> {
+    match &metadata.erasure {
+        Some(info) => erasure::shard_names(info)
+            .iter()
+            .filter_map(|name| all_file_owners.get(name))
+            .flat_map(|metadata| metadata.owners.iter().cloned())
+            .collect(),
+        None => metadata.owners.clone()
+    }
+}
+
+// Versions are handed out by the lowest live owner of the file, so concurrent puts of it never pick the same one
+pub fn next_version(distributed_filename: &str, owners: &[String]) -> BoxedErrorResult<Version> {
+    let membership_list = globals::MEMBERSHIP_LIST.read().clone();
+    let assigner = owners.iter()
+        .filter(|x| membership_list.binary_search(x).is_ok())
+        .min()
+        .ok_or(format!("No live owner of {} to get a version from", distributed_filename))?;
+    rpc::call(assigner, &NextVersionRequest {
+        distributed_filename: distributed_filename.to_string(),
+        known_version: latest_known_version(distributed_filename)
+    })
+}
+
+// The newest version of the file this node has heard of, deleted ones included so that they are never reused.
+// Erasure coded files go by their shards.
+fn latest_known_version(distributed_filename: &str) -> Version {
+    let shard_prefix = format!("{}#", distributed_filename);
+    let is_file_or_shard = |name: &String| name == distributed_filename || name.starts_with(&shard_prefix);
+    let newest_put = globals::ALL_FILE_OWNERS.read()
+        .iter()
+        .filter(|(name, _)| is_file_or_shard(name))
+        .map(|(_, metadata)| metadata.version)
+        .max();
+    let newest_delete = globals::FILE_TOMBSTONES.read()
+        .iter()
+        .filter(|(name, _)| is_file_or_shard(name))
+        .map(|(_, version)| *version)
+        .max();
+    std::cmp::max(newest_put, newest_delete).unwrap_or(0)
+}
+
+// Requests
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StoredReplicasRequest {}
+
+// Asks the lowest live owner for the version a new put should be stored as
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NextVersionRequest {
+    pub distributed_filename: String,
+    // The newest version the writer has heard of, in case the owner hasn't yet
+    pub known_version: Version
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StoredReplica {
+    pub distributed_filename: String,
+    pub version: Version,
+    pub size: u64
+}
+
+impl RpcRequest for StoredReplicasRequest {
+    type Response = Vec<StoredReplica>;
+    const METHOD: &'static str = "stored_replicas";
+    fn handle(&self, _source: &Source) -> BoxedErrorResult<Self::Response> {
+        let mut stored_replicas = Vec::new();
+        for (distributed_filename, versions) in all_local_versions()? {
+            for version in versions {
+                stored_replicas.push(StoredReplica {
+                    size: std::fs::metadata(distributed_file_path(&distributed_filename, version))?.len(),
+                    distributed_filename: distributed_filename.clone(),
+                    version
+                });
             }
-            log("Executed all new_owner operations locally".to_string());
-            Ok(generated_operations)
-        },
-        false => {
-            Ok(vec![])
         }
+        stored_replicas.sort_by(|a, b| (&a.distributed_filename, a.version).cmp(&(&b.distributed_filename, b.version)));
+        Ok(stored_replicas)
+    }
+}
+
+impl RpcRequest for NextVersionRequest {
+    type Response = Version;
+    const METHOD: &'static str = "next_version";
+    // Every run reserves another version
+    const IDEMPOTENT: bool = false;
+    fn handle(&self, _source: &Source) -> BoxedErrorResult<Self::Response> {
+        // Versions handed out for puts that haven't been gossiped yet count too
+        let mut reserved_versions = globals::RESERVED_VERSIONS.get_mut();
+        let newest_version = [
+            latest_known_version(&self.distributed_filename),
+            self.known_version,
+            reserved_versions.get(&self.distributed_filename).cloned().unwrap_or(0)
+        ].iter().cloned().max().unwrap();
+        reserved_versions.insert(self.distributed_filename.clone(), newest_version + 1);
+        Ok(newest_version + 1)
     }
 }
 
@@ -252,46 +1133,121 @@ pub fn handle_failed_node(failed_id: &String) -> BoxedErrorResult<Vec<SendableOp
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct GetOperation {
     pub distributed_filename: String,
-    pub local_path: String
+    pub version: Option<Version>
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct NewFileOwnersOperation {
     pub distributed_filename: String,
     pub new_owners: HashSet<String>,
-    pub from_failure: bool
+    pub from_failure: bool,
+    pub version: Version,
+    pub digest: Option<Digest>,
+    // 0 when the sender doesn't know it
+    pub replicas: u32,
+    pub erasure: Option<ShardInfo>
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+// The contents of the file follow this on the same stream as FileChunkOperations
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SendFileOperation {
     pub filename: String,
-    pub data: Vec<u8>,
+    pub version: Version,
+    pub digest: Digest,
+    pub size: u64,
     pub is_distributed: bool
 }
 
+#[derive(Serialize, Deserialize, Clone)]
+pub struct FileChunkOperation {
+    pub data: Vec<u8>
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct LostFilesOperation {
     pub failed_owner: String,
     pub lost_files: HashSet<String>
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeleteFileOperation {
+    pub distributed_filename: String,
+    pub version: Version
+}
+
+// Sent to everyone by an owner once it dropped its replicas of a deleted file
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeleteAckOperation {
+    pub distributed_filename: String,
+    pub version: Version,
+    pub owner: String
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MoveReplicasOperation {
+    pub distributed_filename: String,
+    pub owners: HashSet<String>,
+    pub placement: Placement,
+    pub version: Version
+}
+
+// Sent by a reader to an owner whose replica didn't match the recorded digest
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BadReplicaOperation {
+    pub distributed_filename: String,
+    pub version: Version,
+    pub digest: Digest
+}
+
+// Sent by a writer to the owners that stored a version whose put missed its write quorum
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DiscardReplicaOperation {
+    pub distributed_filename: String,
+    pub version: Version
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WriteAckOperation {
+    pub filename: String,
+    pub version: Version,
+    pub error: Option<String>
+}
+
 
 // Trait Impls
 impl OperationWriteExecute for GetOperation {
     fn to_bytes(&self) -> BoxedErrorResult<Vec<u8>> {
-        Ok(create_buf(&self, str_to_vec("GET ")))
+        Ok(create_buf(&self, str_to_vec(Self::TAG)))
     }
     fn execute(&self, source: Source) -> BoxedErrorResult<Vec<SendableOperation>> {
-        let local_path = distributed_file_path(&self.distributed_filename);
-        let data_buf = async_std::task::block_on(read_file_to_buf(&local_path))?;
+        // No version means whatever the newest one we hold is
+        let version = match self.version {
+            Some(version) => version,
+            None => *local_versions(&self.distributed_filename)?
+                .last()
+                .ok_or(format!("No versions of {} stored locally", self.distributed_filename))?
+        };
+        let local_path = distributed_file_path(&self.distributed_filename, version);
+        let size = std::fs::metadata(&local_path)?.len();
+        // Hand back the digest recorded when it was stored so the reader can catch on-disk corruption - hashing
+        // the bytes now would vouch for them whatever happened to them
+        let digest = match std::fs::read_to_string(digest_path(&local_path)) {
+            Ok(digest) => digest.trim().to_string(),
+            Err(_) => recorded_digest(&self.distributed_filename, version)
+                .ok_or(format!("No digest recorded for {}@{}", self.distributed_filename, version))?
+        };
+        let stream = TryInto::<ClusterStream>::try_into(source)?;
         let operation = SendableOperation::for_single_tcp_stream(
-            TryInto::<async_std::net::TcpStream>::try_into(source)?,
+            stream.clone(),
             Box::new(SendFileOperation {
-                filename: self.local_path.clone(),
-                data: data_buf,
+                filename: self.distributed_filename.clone(),
+                version,
+                digest,
+                size,
                 is_distributed: false
             }));
-        async_std::task::block_on(operation.write_all_tcp_async());
+        async_std::task::block_on(operation.write_all_tcp_async())?;
+        async_std::task::block_on(send_file_body(&mut vec![stream], &local_path))?;
         Ok(vec![])
     }
     fn to_string(&self) -> String { format!("{:?}", self) }
@@ -299,25 +1255,54 @@ impl OperationWriteExecute for GetOperation {
 
 impl OperationWriteExecute for NewFileOwnersOperation {
     fn to_bytes(&self) -> BoxedErrorResult<Vec<u8>> {
-        Ok(create_buf(&self, str_to_vec("NFO ")))
+        Ok(create_buf(&self, str_to_vec(Self::TAG)))
     }
     fn execute(&self, source: Source) -> BoxedErrorResult<Vec<SendableOperation>> {
+        // Only a version newer than the deleted one is allowed to bring the file back
+        let mut file_tombstones = globals::FILE_TOMBSTONES.get_mut();
+        if let Some(deleted_version) = file_tombstones.get(&self.distributed_filename) {
+            if self.version <= *deleted_version {
+                return Ok(vec![]);
+            }
+            file_tombstones.remove(&self.distributed_filename);
+            globals::TOMBSTONE_STATES.get_mut().remove(&self.distributed_filename);
+        }
+        drop(file_tombstones);
         let mut all_file_owners = globals::ALL_FILE_OWNERS.get_mut();
-        let mut file_owners = all_file_owners.entry(self.distributed_filename.clone()).or_insert(HashSet::new());
-        let added_owners = &self.new_owners - file_owners;
-        match added_owners.len() {
-            0 => {
+        let metadata = all_file_owners.entry(self.distributed_filename.clone()).or_insert(FileMetadata::new());
+        let added_owners = &self.new_owners - &metadata.owners;
+        match (added_owners.len(), self.version > metadata.version) {
+            (0, false) => {
                 Ok(vec![])
             },
             _ => {
                 let mut generated_operations = vec![SendableOperation::for_successors(Box::new(self.clone()))];
                 
-                *file_owners = &self.new_owners | file_owners;
+                metadata.owners = &self.new_owners | &metadata.owners;
+                if metadata.erasure.is_none() {
+                    metadata.erasure = self.erasure.clone();
+                }
+                let is_shard = metadata.erasure.is_some();
+                let old_replicas = metadata.target_replicas();
+                metadata.merge_replicas(self.replicas, self.version);
+                // New files start out where the put placed them, only existing ones may need to move
+                let replicas_changed = metadata.version > 0 && metadata.target_replicas() != old_replicas;
+                metadata.version = std::cmp::max(metadata.version, self.version);
+                if let Some(digest) = &self.digest {
+                    metadata.digests.insert(self.version, digest.clone());
+                }
                 // Need to drop all_file_owners since get_distributed_file needs to read the owners of the files
                 drop(all_file_owners);
+                if replicas_changed {
+                    schedule_rebalance();
+                }
 
+                // A lost shard has no other copy to fetch, so it is rebuilt out of its siblings instead
                 if self.from_failure && added_owners.contains(&*globals::MY_ID.read()) {
-                    async_std::task::block_on(get_distributed_file(&self.distributed_filename))?;
+                    match is_shard {
+                        true  => erasure::spawn_regenerate_shard(self.distributed_filename.clone()),
+                        false => spawn_get_distributed_file(self.distributed_filename.clone())
+                    }
                 }
                 Ok(generated_operations)
             }
@@ -328,50 +1313,88 @@ impl OperationWriteExecute for NewFileOwnersOperation {
 
 impl OperationWriteExecute for SendFileOperation {
     fn to_bytes(&self) -> BoxedErrorResult<Vec<u8>> {
-        Ok(create_buf(&self, str_to_vec("FILE")))
+        Ok(create_buf(&self, str_to_vec(Self::TAG)))
     }
     fn execute(&self, source: Source) -> BoxedErrorResult<Vec<SendableOperation>> {
-        // TODO: Check if the file exists before overwriting
-        let filename = match self.is_distributed {
-            true  => format!("{}/{}", constants::DATA_DIR, self.filename),
-            false => self.filename.clone()
+        // Files sent back for a get are read by whoever asked for them, so an unsolicited one must not get to pick
+        // a local path to write to
+        if !self.is_distributed {
+            return Err(format!("Refusing unsolicited local file {}", self.filename).into());
+        }
+        // The contents can only follow over TCP
+        let mut stream = TryInto::<ClusterStream>::try_into(source)?;
+        let result = async_std::task::block_on(self.receive(&mut stream));
+        // Replicas let the writer know how it went so that it can count towards the quorum
+        let ack = SendableOperation::for_single_tcp_stream(stream, Box::new(WriteAckOperation {
+            filename: self.filename.clone(),
+            version: self.version,
+            error: result.as_ref().err().map(|e| e.to_string())
+        }));
+        async_std::task::block_on(ack.write_all_tcp_async())?;
+        result?;
+        Ok(vec![])
+    }
+    fn to_string(&self) -> String { format!("{:?}", self) }
+}
+
+impl SendFileOperation {
+    async fn receive(&self, stream: &mut ClusterStream) -> BoxedErrorResult<()> {
+        let filename = distributed_file_path(&self.filename, self.version);
+        // Land it in a part file first so a cut off transfer never replaces a good copy
+        let part_path = format!("{}.part", filename);
+        let actual_digest = match receive_file_body(stream, self.size, &part_path).await {
+            Ok(digest) => digest,
+            Err(e) => {
+                let _ = std::fs::remove_file(&part_path);
+                return Err(e);
+            }
         };
-        let mut file = std::fs::OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(filename)?;
-        file.write_all(&self.data);
+        // Replicas never store something that doesn't match what the writer sent
+        if actual_digest != self.digest {
+            std::fs::remove_file(&part_path)?;
+            return Err(format!("Checksum mismatch on {}@{}, expected {} but got {}",
+                               self.filename, self.version, self.digest, actual_digest).into())
+        }
+        std::fs::rename(&part_path, &filename)?;
+        std::fs::write(digest_path(&filename), &self.digest)?;
+        Ok(())
+    }
+}
+
+impl OperationWriteExecute for FileChunkOperation {
+    fn to_bytes(&self) -> BoxedErrorResult<Vec<u8>> {
+        Ok(create_buf(&self, str_to_vec(Self::TAG)))
+    }
+    fn execute(&self, _source: Source) -> BoxedErrorResult<Vec<SendableOperation>> {
+        // Chunks are read directly by whoever is receiving the file, so a stray one has nothing to do
         Ok(vec![])
     }
     fn to_string(&self) -> String { format!("{:?}", self) }
 }
 
-impl fmt::Debug for SendFileOperation {
+impl fmt::Debug for FileChunkOperation {
     fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
         let formatted_data  = if self.data.len() > 40 {
             format!("{:?}...", &self.data[..40])
         } else {
             format!("{:?}", &self.data)
         }; 
-        fmt.debug_struct("SendFileOperation")
-            .field("filename", &self.filename)
+        fmt.debug_struct("FileChunkOperation")
             .field("data", &formatted_data)
-            .field("is_distributed", &self.is_distributed)
             .finish()
     }
 }
 
 impl OperationWriteExecute for LostFilesOperation {
     fn to_bytes(&self) -> BoxedErrorResult<Vec<u8>> {
-        Ok(create_buf(&self, str_to_vec("LOST")))
+        Ok(create_buf(&self, str_to_vec(Self::TAG)))
     }
     fn execute(&self, source: Source) -> BoxedErrorResult<Vec<SendableOperation>> {
         let mut did_remove = false;
         let mut all_file_owners = globals::ALL_FILE_OWNERS.get_mut();
         for lost_file in &self.lost_files {
-            if let Some(owners) = all_file_owners.get_mut(lost_file) {
-                did_remove |= owners.remove(&self.failed_owner);
+            if let Some(metadata) = all_file_owners.get_mut(lost_file) {
+                did_remove |= metadata.owners.remove(&self.failed_owner);
             }
         }
         if did_remove {
@@ -382,3 +1405,162 @@ impl OperationWriteExecute for LostFilesOperation {
     }
     fn to_string(&self) -> String { format!("{:?}", self) }
 }
+
+impl OperationWriteExecute for BadReplicaOperation {
+    fn to_bytes(&self) -> BoxedErrorResult<Vec<u8>> {
+        Ok(create_buf(&self, str_to_vec(Self::TAG)))
+    }
+    fn execute(&self, _source: Source) -> BoxedErrorResult<Vec<SendableOperation>> {
+        let local_path = distributed_file_path(&self.distributed_filename, self.version);
+        if !std::path::Path::new(&local_path).exists() {
+            return Ok(vec![]);
+        }
+        // Check for ourselves rather than take the reader's word for it
+        let recorded_digest = match recorded_digest(&self.distributed_filename, self.version) {
+            Some(recorded_digest) => recorded_digest,
+            None => return Ok(vec![])
+        };
+        if digest_of_file(&local_path)? == recorded_digest {
+            log(format!("Replica of {}@{} reported bad but matches its digest", self.distributed_filename, self.version))?;
+            return Ok(vec![]);
+        }
+        // Drop the corrupt copy and fetch or rebuild it like a newly assigned owner would
+        log(format!("Replacing corrupt replica of {}@{}", self.distributed_filename, self.version))?;
+        std::fs::remove_file(&local_path)?;
+        let _ = std::fs::remove_file(digest_path(&local_path));
+        let is_shard = globals::ALL_FILE_OWNERS.read()
+            .get(&self.distributed_filename)
+            .is_some_and(|metadata| metadata.erasure.is_some());
+        match is_shard {
+            true  => erasure::spawn_regenerate_shard(self.distributed_filename.clone()),
+            false => spawn_get_distributed_file(self.distributed_filename.clone())
+        }
+        Ok(vec![])
+    }
+    fn to_string(&self) -> String { format!("{:?}", self) }
+}
+
+impl OperationWriteExecute for DiscardReplicaOperation {
+    fn to_bytes(&self) -> BoxedErrorResult<Vec<u8>> {
+        Ok(create_buf(&self, str_to_vec(Self::TAG)))
+    }
+    fn execute(&self, _source: Source) -> BoxedErrorResult<Vec<SendableOperation>> {
+        // A recorded digest means the version was committed after all, so it is not ours to drop
+        if recorded_digest(&self.distributed_filename, self.version).is_some() {
+            return Ok(vec![]);
+        }
+        let local_path = distributed_file_path(&self.distributed_filename, self.version);
+        match std::fs::remove_file(&local_path) {
+            Ok(()) => log(format!("Discarded the uncommitted replica {}", local_path))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e.into())
+        }
+        let _ = std::fs::remove_file(digest_path(&local_path));
+        Ok(vec![])
+    }
+    fn to_string(&self) -> String { format!("{:?}", self) }
+}
+
+impl OperationWriteExecute for DeleteFileOperation {
+    fn to_bytes(&self) -> BoxedErrorResult<Vec<u8>> {
+        Ok(create_buf(&self, str_to_vec(Self::TAG)))
+    }
+    fn execute(&self, _source: Source) -> BoxedErrorResult<Vec<SendableOperation>> {
+        // Leave a tombstone so late gossip about the file cannot resurrect it
+        let mut file_tombstones = globals::FILE_TOMBSTONES.get_mut();
+        if let Some(deleted_version) = file_tombstones.get(&self.distributed_filename) {
+            if *deleted_version >= self.version {
+                return Ok(vec![]);
+            }
+        }
+        file_tombstones.insert(self.distributed_filename.clone(), self.version);
+        drop(file_tombstones);
+        // A put that raced ahead of the delete keeps the file alive
+        let mut all_file_owners = globals::ALL_FILE_OWNERS.get_mut();
+        let owners = match all_file_owners.get(&self.distributed_filename) {
+            Some(metadata) if metadata.version <= self.version => {
+                all_file_owners.remove(&self.distributed_filename).unwrap().owners
+            },
+            _ => HashSet::new()
+        };
+        drop(all_file_owners);
+        globals::TOMBSTONE_STATES.get_mut().insert(self.distributed_filename.clone(), TombstoneState {
+            unacked_owners: owners.clone(),
+            acked_at: None
+        });
+        // Remove the replicas we have - stale copies included
+        let had_replicas = local_versions(&self.distributed_filename)?.iter().any(|version| *version <= self.version);
+        remove_local_versions(&self.distributed_filename, self.version)?;
+        let mut generated_operations = vec![SendableOperation::for_successors(Box::new(self.clone()))];
+        let my_id = globals::MY_ID.read().clone();
+        if had_replicas || owners.contains(&my_id) {
+            generated_operations.push(SendableOperation::for_everyone(Box::new(DeleteAckOperation {
+                distributed_filename: self.distributed_filename.clone(),
+                version: self.version,
+                owner: my_id
+            })));
+        }
+        Ok(generated_operations)
+    }
+    fn to_string(&self) -> String { format!("{:?}", self) }
+}
+
+impl OperationWriteExecute for DeleteAckOperation {
+    fn to_bytes(&self) -> BoxedErrorResult<Vec<u8>> {
+        Ok(create_buf(&self, str_to_vec(Self::TAG)))
+    }
+    fn execute(&self, source: Source) -> BoxedErrorResult<Vec<SendableOperation>> {
+        // The ack can overtake the delete on its way around the ring
+        let mut generated_operations = vec![];
+        let deleted_version = globals::FILE_TOMBSTONES.read().get(&self.distributed_filename).cloned();
+        if deleted_version.is_none_or(|deleted_version| deleted_version < self.version) {
+            let delete = DeleteFileOperation {
+                distributed_filename: self.distributed_filename.clone(),
+                version: self.version
+            };
+            generated_operations = delete.execute(source)?;
+        }
+        // An ack for an older delete says nothing about the replicas of a newer one
+        if globals::FILE_TOMBSTONES.read().get(&self.distributed_filename) == Some(&self.version) {
+            if let Some(state) = globals::TOMBSTONE_STATES.get_mut().get_mut(&self.distributed_filename) {
+                state.unacked_owners.remove(&self.owner);
+            }
+        }
+        Ok(generated_operations)
+    }
+    fn to_string(&self) -> String { format!("{:?}", self) }
+}
+
+impl OperationWriteExecute for MoveReplicasOperation {
+    fn to_bytes(&self) -> BoxedErrorResult<Vec<u8>> {
+        Ok(create_buf(&self, str_to_vec(Self::TAG)))
+    }
+    fn execute(&self, _source: Source) -> BoxedErrorResult<Vec<SendableOperation>> {
+        let mut all_file_owners = globals::ALL_FILE_OWNERS.get_mut();
+        let metadata = match all_file_owners.get_mut(&self.distributed_filename) {
+            Some(metadata) if self.placement > metadata.placement => metadata,
+            _ => return Ok(vec![])
+        };
+        let was_owner = metadata.owners.contains(&*globals::MY_ID.read());
+        metadata.owners = self.owners.clone();
+        metadata.placement = self.placement.clone();
+        drop(all_file_owners);
+        // Versions written after the rebalance started are not ours to drop
+        if was_owner && !self.owners.contains(&*globals::MY_ID.read()) {
+            remove_local_versions(&self.distributed_filename, self.version)?;
+        }
+        Ok(vec![SendableOperation::for_successors(Box::new(self.clone()))])
+    }
+    fn to_string(&self) -> String { format!("{:?}", self) }
+}
+
+impl OperationWriteExecute for WriteAckOperation {
+    fn to_bytes(&self) -> BoxedErrorResult<Vec<u8>> {
+        Ok(create_buf(&self, str_to_vec(Self::TAG)))
+    }
+    fn execute(&self, _source: Source) -> BoxedErrorResult<Vec<SendableOperation>> {
+        // Acks are read directly by the writer waiting on them, so a stray one has nothing left to do
+        Ok(vec![])
+    }
+    fn to_string(&self) -> String { format!("{:?}", self) }
+}
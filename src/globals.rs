@@ -1,24 +1,47 @@
 use async_std;
-use crate::heartbeat::Timestamp;
+use crate::auth::SeenNonces;
+use crate::config::Config;
+use crate::filesystem::{FileMetadata, NodeState, TombstoneState, Version};
+use crate::failure_detector::DetectorState;
+use crate::hash_ring::HashRing;
+use crate::heartbeat::Incarnation;
 use crate::locks::*;
+use crate::rpc::RequestId;
+use crate::tls::TlsContext;
 use std;
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, VecDeque};
 use std::fs::{File, OpenOptions};
+use std::time::Instant;
 
 // Vars
 lazy_static! {
+    pub static ref CONFIG: RwLockOption<Config> = RwLockOption::new();
     pub static ref DEBUG: RwLockOption<bool> = RwLockOption::new();
     pub static ref LOG_FILE: RwLockOption<File> = RwLockOption::new();
     pub static ref UDP_SOCKET: RwLockOption<std::net::UdpSocket> = RwLockOption::new();
     pub static ref IS_JOINED: RwLockOption<bool> = RwLockOption::new();
     pub static ref MEMBERSHIP_LIST: RwLockOption<Vec<String>> = RwLockOption::new();
     pub static ref SUCCESSOR_LIST: RwLockOption<Vec<String>> = RwLockOption::new();
-    pub static ref PREDECESSOR_LIST: RwLockOption<Vec<String>> = RwLockOption::new();
-    pub static ref PREDECESSOR_TIMESTAMPS: RwLockOption<HashMap<String, Timestamp>> = RwLockOption::new();
+    pub static ref INCARNATIONS: RwLockOption<HashMap<String, Incarnation>> = RwLockOption::new();
+    pub static ref DEPARTED_AT: RwLockOption<HashMap<String, Instant>> = RwLockOption::new();
+    pub static ref MEMBER_WEIGHTS: RwLockOption<HashMap<String, u32>> = RwLockOption::new();
+    pub static ref HASH_RING: RwLockOption<HashRing> = RwLockOption::new();
+    pub static ref DETECTOR_STATE: RwLockOption<DetectorState> = RwLockOption::new();
     pub static ref MY_IP_ADDR: RwLockOption<String> = RwLockOption::new();
     pub static ref MY_ID: RwLockOption<String> = RwLockOption::new();
     pub static ref TCP_ADDR: RwLockOption<String> = RwLockOption::new();
     pub static ref SERVER_SOCKET: RwLockOption<async_std::net::TcpListener> = RwLockOption::new();
     pub static ref UDP_TO_TCP_MAP: RwLockOption<HashMap<String, String>> = RwLockOption::new();
-    pub static ref ALL_FILE_OWNERS: RwLockOption<HashMap<String, HashSet<String>>> = RwLockOption::new();
+    pub static ref ALL_FILE_OWNERS: RwLockOption<HashMap<String, FileMetadata>> = RwLockOption::new();
+    pub static ref FILE_TOMBSTONES: RwLockOption<HashMap<String, Version>> = RwLockOption::new();
+    pub static ref TOMBSTONE_STATES: RwLockOption<HashMap<String, TombstoneState>> = RwLockOption::new();
+    pub static ref RESERVED_VERSIONS: RwLockOption<HashMap<String, Version>> = RwLockOption::new();
+    pub static ref PREVIOUS_STATE: RwLockOption<NodeState> = RwLockOption::new();
+    pub static ref REBALANCE_NEEDED: RwLockOption<bool> = RwLockOption::new();
+    pub static ref REBALANCE_QUEUE: RwLockOption<VecDeque<String>> = RwLockOption::new();
+    pub static ref NEXT_REQUEST_ID: RwLockOption<RequestId> = RwLockOption::new();
+    pub static ref PROTOCOL_VERSION: RwLockOption<u16> = RwLockOption::new();
+    pub static ref TLS: RwLockOption<Option<TlsContext>> = RwLockOption::new();
+    pub static ref UDP_SECRET: RwLockOption<Option<Vec<u8>>> = RwLockOption::new();
+    pub static ref SEEN_NONCES: RwLockOption<SeenNonces> = RwLockOption::new();
 }
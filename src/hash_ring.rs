@@ -0,0 +1,49 @@
+use crate::constants::MAX_WEIGHT;
+use crate::easyhash::{EasyHash, HashAlgorithm};
+use std::collections::HashMap;
+
+// Consistent hashing for file placement. Every member is hashed onto the ring at virtual_nodes * weight points
+// and a file is owned by the first distinct members found walking clockwise from the file's own hash, so a join
+// or leave only moves the files next to the changed member's points - roughly 1/N of them.
+#[derive(Debug, Clone)]
+pub struct HashRing {
+    points: Vec<(u64, String)>,
+    algorithm: HashAlgorithm
+}
+
+impl HashRing {
+    pub fn new(members: &[String], weights: &HashMap<String, u32>, virtual_nodes: u32, algorithm: HashAlgorithm) -> Self {
+        let mut points = Vec::new();
+        for member in members.iter() {
+            // Clamped rather than trusted since it comes from the member itself
+            let weight = weights.get(member).cloned().unwrap_or(1).clamp(1, MAX_WEIGHT);
+            for i in 0..virtual_nodes.saturating_mul(weight) {
+                points.push((format!("{}#{}", member, i).easyhash(algorithm), member.clone()));
+            }
+        }
+        // Ties are broken by the id so every node builds the exact same ring
+        points.sort();
+        HashRing { points, algorithm }
+    }
+
+    pub fn owners(&self, key: &str, count: usize) -> Vec<String> {
+        let mut owners: Vec<String> = Vec::new();
+        if self.points.is_empty() {
+            return owners
+        }
+        let key_hash = key.easyhash(self.algorithm);
+        let start = match self.points.binary_search_by(|(point, _)| point.cmp(&key_hash)) {
+            Ok(idx) | Err(idx) => idx
+        };
+        for i in 0..self.points.len() {
+            if owners.len() == count {
+                break;
+            }
+            let member = &self.points[(start + i) % self.points.len()].1;
+            if !owners.contains(member) {
+                owners.push(member.clone());
+            }
+        }
+        owners
+    }
+}
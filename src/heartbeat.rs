@@ -1,45 +1,22 @@
 use crate::BoxedErrorResult;
 use crate::component_manager::*;
 use crate::constants;
-use crate::filesystem;
+use crate::failure_detector;
+use crate::filesystem::{self, FileMetadata, Version};
 use crate::globals;
+use crate::hash_ring::HashRing;
 use crate::modular::*;
 use crate::operation::*;
 use serde::{Serialize, Deserialize};
-use std::collections::{HashMap, HashSet};
+use std::collections::HashMap;
 use std::convert::TryInto;
-use std::iter::FromIterator;
-use std::time::SystemTime;
+use std::time::{Duration, Instant, SystemTime};
 
 type HeartBeatResult = BoxedErrorResult<()>;
+// Milliseconds since the epoch - only used for ids and logs, intervals are measured with Instant
 pub type Timestamp = u64;
-
-pub fn maintainer(sender: &OperationSender) -> ComponentResult {
-    if !is_joined() {
-        return Ok(())
-    }
-    // Figure out who is expired
-    let mut expired_nodes: Vec<String> = Vec::new();
-    for predecessor in globals::PREDECESSOR_LIST.read().iter() {
-        if is_expired(&predecessor)? {
-            expired_nodes.push(predecessor.clone());
-        }
-    }
-    if expired_nodes.len() > 0 {
-        // Remove the expired lads and let everyone know
-        for expired_node in expired_nodes.iter() {
-            log(format!("Detected a failure on node {}", &expired_node));
-            let leave_operation = LeaveOperation{
-                id: expired_node.clone()
-            };
-            let mut generated_operations = leave_operation.execute(Source::myself())?;
-            for generated_operation in generated_operations.drain(..) {
-                sender.send(generated_operation)?;
-            }
-        }
-    }
-    Ok(())
-}
+// Bumped by a member whenever it has to refute a suspicion or eviction about itself
+pub type Incarnation = u64;
 
 pub fn join(_args: Vec<&str>, sender: &OperationSender) -> HeartBeatResult {
     if is_joined() {
@@ -51,17 +28,44 @@ pub fn join(_args: Vec<&str>, sender: &OperationSender) -> HeartBeatResult {
     let my_id = gen_id()?;
     globals::MY_ID.write(my_id.clone());
     globals::MEMBERSHIP_LIST.get_mut().push(my_id.clone());
+    globals::INCARNATIONS.get_mut().insert(my_id.clone(), 0);
+    globals::MEMBER_WEIGHTS.get_mut().insert(my_id.clone(), globals::CONFIG.read().weight);
+    rebuild_hash_ring();
     globals::UDP_TO_TCP_MAP.get_mut().insert(ip_from_id(&my_id), globals::TCP_ADDR.read().clone());
-    // Send Join operation to everyone
-    let dests = constants::IP_LIST.iter().map(|x| x.to_string()).collect();
+    // Send Join operation to everyone - members from before a restart may still be up even if the introducers are not
+    let mut dests = globals::CONFIG.read().introducers.clone();
+    let previous_state = globals::PREVIOUS_STATE.read().clone();
+    for member_id in previous_state.membership_list.iter() {
+        let member_ip = ip_from_id(member_id);
+        if member_ip != *globals::MY_IP_ADDR.read() && !dests.contains(&member_ip) {
+            dests.push(member_ip);
+        }
+    }
     let join_item = SendableOperation {
-        dests: Destinations::UDPAddr(dests),
+        dests: Destinations::UDPAddr(dests.clone()),
         operation: Box::new(JoinOperation {
             id: my_id.clone(),
-            tcp_addr: globals::TCP_ADDR.read().clone()
+            tcp_addr: globals::TCP_ADDR.read().clone(),
+            incarnation: 0,
+            weight: globals::CONFIG.read().weight,
+            min_protocol_version: constants::MIN_PROTOCOL_VERSION,
+            max_protocol_version: constants::PROTOCOL_VERSION
         })
     };
     sender.send(join_item)?;
+    // Our id from before a restart is dead, so let everyone forget it instead of waiting for it to expire
+    if let Some(previous_id) = previous_state.id {
+        if previous_id != my_id {
+            sender.send(SendableOperation {
+                dests: Destinations::UDPAddr(dests),
+                operation: Box::new(LeaveOperation {
+                    id: previous_id,
+                    // The old id never comes back, so no incarnation of it can refute this
+                    incarnation: Incarnation::MAX
+                })
+            })?;
+        }
+    }
     log(format!("Joined the network with id: {}", my_id));
     Ok(())
 }
@@ -71,7 +75,8 @@ pub fn leave(_args: Vec<&str>, sender: &OperationSender) -> HeartBeatResult {
     // Send the leave operation and clear vars
     let my_id = globals::MY_ID.read().clone();
     let leave_item = SendableOperation::for_successors(Box::new(LeaveOperation{
-        id: my_id.clone()
+        id: my_id.clone(),
+        incarnation: my_incarnation()
     }));
     sender.send(leave_item)?;
     clear_vars_on_leave();
@@ -80,30 +85,77 @@ pub fn leave(_args: Vec<&str>, sender: &OperationSender) -> HeartBeatResult {
 }
 
 pub fn print(_args: Vec<&str>) -> HeartBeatResult {
-    let list = globals::MEMBERSHIP_LIST.read();
+    let list = globals::MEMBERSHIP_LIST.read().clone();
+    let my_id = if is_joined() { globals::MY_ID.read().clone() } else { String::new() };
     println!("[");
     for memb in list.iter() {
-        println!("  {}", memb);
+        let incarnation = incarnation_of(memb).unwrap_or(0);
+        if *memb == my_id {
+            println!("  {} (self, incarnation {})", memb, incarnation);
+            continue;
+        }
+        match failure_detector::last_seen(memb) {
+            Some(elapsed) => println!("  {} (incarnation {}, last seen {} ms ago)", memb, incarnation, elapsed.as_millis()),
+            None => println!("  {} (incarnation {}, not heard from yet)", memb, incarnation)
+        }
     }
     println!("]");
     Ok(())
 }
 
-pub fn send_heartbeats() -> HeartBeatResult {
-    let udp_socket = globals::UDP_SOCKET.read();
-    let successor_list = globals::SUCCESSOR_LIST.read().clone();
-    let heartbeat_operation = Box::new(HeartbeatOperation {
-        id: globals::MY_ID.read().clone()
-    });
-    let heartbeats = SendableOperation::for_successors(heartbeat_operation);
-    heartbeats.write_all_udp(&udp_socket)
+// Helpers
+pub fn is_member(id: &String) -> bool {
+    globals::MEMBERSHIP_LIST.read().binary_search(id).is_ok()
 }
 
-// Helpers
-pub fn is_master() -> bool {
-    // TODO: Eliminate the need for a master in the final product
-    let membership_list = globals::MEMBERSHIP_LIST.read();
-    membership_list.len() > 0 && membership_list[0] == *globals::MY_ID.read()
+pub fn incarnation_of(id: &String) -> Option<Incarnation> {
+    globals::INCARNATIONS.read().get(id).cloned()
+}
+
+pub fn my_incarnation() -> Incarnation {
+    incarnation_of(&globals::MY_ID.read()).unwrap_or(0)
+}
+
+// Records the incarnation of a member, returning whether it is newer than anything heard before
+pub fn observe_incarnation(id: &String, incarnation: Incarnation) -> bool {
+    let mut incarnations = globals::INCARNATIONS.get_mut();
+    match incarnations.get(id) {
+        Some(known) if *known >= incarnation => false,
+        _ => {
+            incarnations.insert(id.clone(), incarnation);
+            true
+        }
+    }
+}
+
+// Adds a member unless it already left or was evicted at this incarnation or a later one
+pub fn admit_member(id: &String, incarnation: Incarnation) -> BoxedErrorResult<bool> {
+    let newer = observe_incarnation(id, incarnation);
+    if is_member(id) {
+        if newer {
+            failure_detector::clear_suspicion(id);
+        }
+        return Ok(true)
+    }
+    if newer {
+        insert_node(id)?;
+    }
+    Ok(newer)
+}
+
+// Someone gave up on us while we are still up, so outbid them and announce ourselves to everyone again
+pub fn refute(heard_incarnation: Incarnation) -> BoxedErrorResult<Vec<SendableOperation>> {
+    let my_id = globals::MY_ID.read().clone();
+    let incarnation = std::cmp::max(my_incarnation(), heard_incarnation.saturating_add(1));
+    globals::INCARNATIONS.get_mut().insert(my_id.clone(), incarnation);
+    log(format!("Refuting our failure with incarnation {}", incarnation))?;
+    failure_detector::announce_alive(&my_id, incarnation);
+    Ok(vec![SendableOperation::for_everyone(Box::new(NewMemberOperation {
+        id: my_id,
+        tcp_addr: globals::TCP_ADDR.read().clone(),
+        incarnation,
+        weight: globals::CONFIG.read().weight
+    }))])
 }
 
 pub fn ips_from_ids(ids: &Vec<String>) -> Vec<String> {
@@ -112,9 +164,12 @@ pub fn ips_from_ids(ids: &Vec<String>) -> Vec<String> {
     }).collect()
 }
 
+// Ids come off the wire, so a malformed one is treated as a bare address instead of taken down with a panic
 pub fn ip_from_id(id: &String) -> String {
-    let n = id.find('|').unwrap();
-    String::from(&id[..n])    
+    match id.find('|') {
+        Some(n) => String::from(&id[..n]),
+        None => id.clone()
+    }
 }
 
 pub fn tcp_ips_from_udp_ips(udp_ips: &Vec<String>) -> BoxedErrorResult<Vec<String>> {
@@ -136,6 +191,27 @@ pub fn insert_node(new_id: &String) -> HeartBeatResult {
     let mut membership_list = globals::MEMBERSHIP_LIST.get_mut();
     if let Err(idx) = membership_list.binary_search(&new_id) {
         membership_list.insert(idx, new_id.to_string());
+        // The ring just changed under the files, so some of them belong somewhere else now
+        filesystem::schedule_rebalance();
+    }
+    Ok(())
+}
+
+// Drops what is left of members that departed more than departed_member_ttl ago, unless they came back since
+pub fn forget_departed_members() -> BoxedErrorResult<()> {
+    let ttl = Duration::from_millis(globals::CONFIG.read().departed_member_ttl);
+    let mut departed_at = globals::DEPARTED_AT.get_mut();
+    let expired: Vec<String> = departed_at.iter()
+        .filter(|(_, at)| at.elapsed() >= ttl)
+        .map(|(id, _)| id.clone())
+        .collect();
+    for id in expired {
+        departed_at.remove(&id);
+        if !is_member(&id) {
+            globals::INCARNATIONS.get_mut().remove(&id);
+            globals::MEMBER_WEIGHTS.get_mut().remove(&id);
+            log(format!("Forgot departed member {}", id))?;
+        }
     }
     Ok(())
 }
@@ -155,15 +231,20 @@ fn clear_vars_on_leave() -> BoxedErrorResult<()> {
     globals::IS_JOINED.write(false);
     globals::MEMBERSHIP_LIST.get_mut().clear();
     globals::SUCCESSOR_LIST.get_mut().clear();
-    globals::PREDECESSOR_LIST.get_mut().clear();
+    globals::INCARNATIONS.get_mut().clear();
+    globals::DEPARTED_AT.get_mut().clear();
+    globals::MEMBER_WEIGHTS.get_mut().clear();
+    rebuild_hash_ring();
+    failure_detector::reset();
+    globals::PROTOCOL_VERSION.write(constants::PROTOCOL_VERSION);
     // Does keeping the old id matter? If so, edit the locks to be able to write None back in
     Ok(())
 }
 
-pub fn merge_membership_list(membership_list: &Vec<String>) -> HeartBeatResult {
+pub fn merge_membership_list(membership_list: &Vec<String>, incarnations: &HashMap<String, Incarnation>) -> HeartBeatResult {
     // TODO: Optimize?
     for member_id in membership_list {
-        insert_node(member_id)?;
+        admit_member(member_id, incarnations.get(member_id).cloned().unwrap_or(0))?;
     }
     Ok(())
 }
@@ -173,24 +254,73 @@ pub fn merge_tcp_map(udp_to_tcp_map: &HashMap<String, String>) -> HeartBeatResul
     Ok(())
 }
 
-pub fn merge_all_file_owners(new_file_owners: &HashMap<String, HashSet<String>>) -> HeartBeatResult {
+pub fn merge_member_weights(member_weights: &HashMap<String, u32>) -> HeartBeatResult {
+    globals::MEMBER_WEIGHTS.get_mut().extend(member_weights.clone());
+    Ok(())
+}
+
+pub fn merge_all_file_owners(new_file_owners: &HashMap<String, FileMetadata>) -> HeartBeatResult {
     let mut all_file_owners = globals::ALL_FILE_OWNERS.get_mut();
-    for (filename, new_owners) in new_file_owners.iter() {
-        let mut file_owners = all_file_owners.entry(filename.to_string()).or_insert(HashSet::new());
+    let file_tombstones = globals::FILE_TOMBSTONES.read();
+    for (filename, new_metadata) in new_file_owners.iter() {
+        // Deleted versions must not be brought back by a stale view
+        if let Some(deleted_version) = file_tombstones.get(filename) {
+            if new_metadata.version <= *deleted_version {
+                continue;
+            }
+        }
+        let metadata = all_file_owners.entry(filename.to_string()).or_insert(FileMetadata::new());
         // TODO: Another place to reduce clones/allocations -> Cows could help here and in a lot of other places
-        *file_owners = file_owners.union(new_owners).map(|x| x.to_string()).collect();
+        // A newer placement replaces the owners outright, otherwise the views are unioned
+        if new_metadata.placement > metadata.placement {
+            metadata.owners = new_metadata.owners.clone();
+            metadata.placement = new_metadata.placement.clone();
+        } else if new_metadata.placement == metadata.placement {
+            metadata.owners = metadata.owners.union(&new_metadata.owners).map(|x| x.to_string()).collect();
+        }
+        metadata.merge_replicas(new_metadata.replicas, new_metadata.version);
+        if metadata.erasure.is_none() {
+            metadata.erasure = new_metadata.erasure.clone();
+        }
+        metadata.version = std::cmp::max(metadata.version, new_metadata.version);
+        metadata.digests.extend(new_metadata.digests.clone());
+    }
+    Ok(())
+}
+
+pub fn merge_file_tombstones(new_file_tombstones: &HashMap<String, Version>) -> HeartBeatResult {
+    let mut file_tombstones = globals::FILE_TOMBSTONES.get_mut();
+    let mut all_file_owners = globals::ALL_FILE_OWNERS.get_mut();
+    for (filename, version) in new_file_tombstones.iter() {
+        let tombstone = file_tombstones.entry(filename.to_string()).or_insert(0);
+        *tombstone = std::cmp::max(*tombstone, *version);
+        if let Some(metadata) = all_file_owners.get(filename) {
+            if metadata.version <= *tombstone {
+                all_file_owners.remove(filename);
+            }
+        }
     }
     Ok(())
 }
 
 pub fn recalculate_neighbors() -> HeartBeatResult {
-    recalculate_predecessors()?;
     recalculate_successors()?;
+    rebuild_hash_ring();
     log(format!("Membership list is {:?}", &*globals::MEMBERSHIP_LIST.read()));
     log(format!("TCP map is {:?}", &*globals::UDP_TO_TCP_MAP.read()));
     Ok(())
 }
 
+fn rebuild_hash_ring() {
+    let membership_list = globals::MEMBERSHIP_LIST.read().clone();
+    let member_weights = globals::MEMBER_WEIGHTS.read().clone();
+    let (virtual_nodes, hash_algorithm) = {
+        let config = globals::CONFIG.read();
+        (config.virtual_nodes, config.hash_algorithm)
+    };
+    globals::HASH_RING.write(HashRing::new(&membership_list, &member_weights, virtual_nodes, hash_algorithm));
+}
+
 fn recalculate_successors() -> HeartBeatResult {
     let successors = gen_neighbor_list(1)?;
     log(format!("Calculated successors as {:?}", successors));
@@ -198,30 +328,6 @@ fn recalculate_successors() -> HeartBeatResult {
     Ok(())
 }
 
-fn recalculate_predecessors() -> HeartBeatResult {
-    // Get the new predecessors
-    let new_predecessors = gen_neighbor_list(-1)?;
-    log(format!("Calculated predecessors as {:?}", new_predecessors));
-    // Save the old predecessors
-    let mut predecessors = globals::PREDECESSOR_LIST.get_mut();
-    // For each deleted, remove from the timestamp dict
-    let mut predecessor_timestamps = globals::PREDECESSOR_TIMESTAMPS.get_mut();
-    let old_predecessors_set: HashSet<&String> = HashSet::from_iter(predecessors.iter());
-    let new_predecessors_set: HashSet<&String> = HashSet::from_iter(new_predecessors.iter());
-    let deleted_predecessors = &old_predecessors_set - &new_predecessors_set;
-    for deleted_predecessor in deleted_predecessors.iter() {
-        predecessor_timestamps.remove(&deleted_predecessor.to_string());
-    }
-    // For each new guy, add to the timestamp dict
-    let inserted_predecessors = &new_predecessors_set - &old_predecessors_set;
-    for inserted_predecessor in inserted_predecessors.iter() {
-        predecessor_timestamps.insert(inserted_predecessor.to_string(), get_timestamp()?);
-    }
-    // Write the new predecessors
-    *predecessors = new_predecessors;
-    Ok(())
-}
-
 // TODO: Eventually change this code to make sure the data fits
 fn gen_neighbor_list(increment: i32) -> BoxedErrorResult<Vec<String>> {
     // Ensure the membership_list has members
@@ -233,7 +339,8 @@ fn gen_neighbor_list(increment: i32) -> BoxedErrorResult<Vec<String>> {
     // Traverse modulo membership list size
     let my_idx: usize = membership_list.binary_search(&*globals::MY_ID.read())
         .expect("ID of node not found in membership list");
-    gen_neighbor_list_from(my_idx as i32, increment, constants::NUM_SUCCESSORS, false)
+    let num_successors = globals::CONFIG.read().num_successors;
+    gen_neighbor_list_from(my_idx as i32, increment, num_successors, false)
 }
 
 pub fn gen_neighbor_list_from(idx: i32, increment: i32, num_successors: u32, include_self: bool) -> BoxedErrorResult<Vec<String>> {
@@ -255,98 +362,117 @@ pub fn gen_neighbor_list_from(idx: i32, increment: i32, num_successors: u32, inc
     
 }
 
-fn is_expired(id: &String) -> BoxedErrorResult<bool> {
-    let now = get_timestamp()?;
-    match globals::PREDECESSOR_TIMESTAMPS.read().get(&id.to_string()) {
-        Some(last_timestamp) => {
-            if now < *last_timestamp {
-                return Err("Invalid timestamp found in predecessor timestamps".into())
-            }
-            Ok(now - last_timestamp > constants::EXPIRATION_DURATION)
-        }
-        None => Err("Tried to check if non-predecessor node is expired".into())
-    }
-}
-
 fn gen_id() -> BoxedErrorResult<String> {
     Ok(format!("{}|{}", *globals::MY_IP_ADDR.read(), get_timestamp()?).to_string())
 }
 
 pub fn get_timestamp() -> BoxedErrorResult<Timestamp> {
-    Ok(SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs())
+    Ok(SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_millis() as Timestamp)
 }
 
 // Operations
+// Read at any protocol version, so new fields can't be added here without a new tag
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct HeartbeatOperation {
-    pub id: String
+pub struct JoinOperation {
+    pub id: String,
+    pub tcp_addr: String,
+    pub incarnation: Incarnation,
+    pub weight: u32,
+    // The protocol versions the joiner can speak
+    pub min_protocol_version: u16,
+    pub max_protocol_version: u16
 }
 
+// Also read at any protocol version
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct JoinOperation {
-    pub id: String,
-    pub tcp_addr: String 
+pub struct JoinRejectedOperation {
+    pub reason: String
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct LeaveOperation {
-    pub id: String
+    pub id: String,
+    pub incarnation: Incarnation
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct NewMemberOperation {
     pub id: String,
-    pub tcp_addr: String 
+    pub tcp_addr: String,
+    pub incarnation: Incarnation,
+    pub weight: u32
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct MemberInitializationOperation {
     membership_list: Vec<String>,
+    incarnations: HashMap<String, Incarnation>,
+    member_weights: HashMap<String, u32>,
     udp_to_tcp_map: HashMap<String, String>,
-    all_file_owners: HashMap<String, HashSet<String>>
+    all_file_owners: HashMap<String, FileMetadata>,
+    file_tombstones: HashMap<String, Version>,
+    // What the joiner has to write its frames at from now on
+    protocol_version: u16
 }
 
 // Trait Impls
-impl OperationWriteExecute for HeartbeatOperation {
-    fn to_bytes(&self) -> BoxedErrorResult<Vec<u8>> {
-        Ok(create_buf(&self, str_to_vec("HB  ")))
-    }
-    fn execute(&self, source: Source) -> BoxedErrorResult<Vec<SendableOperation>> {
-        // Assert that source and self.id correspond to same ip
-        let src_ip = ip_from_id(&self.id);
-        if src_ip != TryInto::<String>::try_into(source)? {
-            return Err("Received suspicious heartbeat packet with source != id".into())
-        }
-        // Update the most recent observation if this is a predecessor
-        if let Some(predecessor_entry) = globals::PREDECESSOR_TIMESTAMPS.get_mut().get_mut(&self.id) {
-            *predecessor_entry = get_timestamp()?;
-        }
-        // TODO: Maybe add user back in case of accidental leave packet - may need more synchronization
-        Ok(vec![])
-    }
-    fn to_string(&self) -> String { format!("{:?}", self) }
-}
-
 impl OperationWriteExecute for JoinOperation {
     fn to_bytes(&self) -> BoxedErrorResult<Vec<u8>> {
-        Ok(create_buf(&self, str_to_vec("JOIN")))
+        Ok(create_buf(&self, str_to_vec(Self::TAG)))
     }
     fn execute(&self, source: Source) -> BoxedErrorResult<Vec<SendableOperation>> {
         // Add the new guy and send it to everyone
         let mut generated_operations: Vec<SendableOperation> = Vec::new();
-        insert_node(&self.id)?;
-        globals::UDP_TO_TCP_MAP.get_mut().insert(TryInto::<String>::try_into(source)?, self.tcp_addr.clone());
+        // The cluster keeps speaking the version it already agreed on, so the joiner has to be able to speak it too
+        let protocol_version = *globals::PROTOCOL_VERSION.read();
+        if protocol_version < self.min_protocol_version || protocol_version > self.max_protocol_version {
+            let reason = format!("The cluster speaks protocol version {} but {} only speaks {} to {}",
+                                 protocol_version, self.id, self.min_protocol_version, self.max_protocol_version);
+            log(format!("Rejected join: {}", reason))?;
+            let source_addr: String = source.try_into()?;
+            generated_operations.push(SendableOperation {
+                dests: Destinations::UDPAddr(vec![source_addr]),
+                operation: Box::new(JoinRejectedOperation {
+                    reason
+                })
+            });
+            return Ok(generated_operations)
+        }
+        if self.weight == 0 || self.weight > constants::MAX_WEIGHT {
+            let reason = format!("{} has weight {} but weights go from 1 to {}", self.id, self.weight, constants::MAX_WEIGHT);
+            log(format!("Rejected join: {}", reason))?;
+            let source_addr: String = source.try_into()?;
+            generated_operations.push(SendableOperation {
+                dests: Destinations::UDPAddr(vec![source_addr]),
+                operation: Box::new(JoinRejectedOperation {
+                    reason
+                })
+            });
+            return Ok(generated_operations)
+        }
+        if !admit_member(&self.id, self.incarnation)? {
+            // A stale join from before this id left
+            return Ok(generated_operations)
+        }
+        globals::UDP_TO_TCP_MAP.get_mut().insert(ip_from_id(&self.id), self.tcp_addr.clone());
+        globals::MEMBER_WEIGHTS.get_mut().insert(self.id.clone(), self.weight);
         generated_operations.push(
             SendableOperation::for_everyone(Box::new(NewMemberOperation{
                 id: self.id.clone(),
-                tcp_addr: self.tcp_addr.clone()
+                tcp_addr: self.tcp_addr.clone(),
+                incarnation: self.incarnation,
+                weight: self.weight
             }))
         );
         generated_operations.push(
             SendableOperation::for_single(self.id.to_string(), Box::new(MemberInitializationOperation{
                 membership_list: globals::MEMBERSHIP_LIST.read().clone(),
+                incarnations: globals::INCARNATIONS.read().clone(),
+                member_weights: globals::MEMBER_WEIGHTS.read().clone(),
                 udp_to_tcp_map: globals::UDP_TO_TCP_MAP.read().clone(),
-                all_file_owners: globals::ALL_FILE_OWNERS.read().clone()
+                all_file_owners: globals::ALL_FILE_OWNERS.read().clone(),
+                file_tombstones: globals::FILE_TOMBSTONES.read().clone(),
+                protocol_version
             }))
         );
         recalculate_neighbors()?;
@@ -358,12 +484,36 @@ impl OperationWriteExecute for JoinOperation {
 
 impl OperationWriteExecute for LeaveOperation {
     fn to_bytes(&self) -> BoxedErrorResult<Vec<u8>> {
-        Ok(create_buf(&self, str_to_vec("LEAV")))
+        Ok(create_buf(&self, str_to_vec(Self::TAG)))
     }
     fn execute(&self, _source: Source) -> BoxedErrorResult<Vec<SendableOperation>> {
         let mut generated_operations: Vec<SendableOperation> = Vec::new();
+        if !is_joined() {
+            return Ok(generated_operations)
+        }
+        if self.id == *globals::MY_ID.read() {
+            // We are still here, so whoever evicted us was wrong unless they heard about a newer incarnation
+            if self.incarnation >= my_incarnation() {
+                generated_operations.append(&mut refute(self.incarnation)?);
+            }
+            return Ok(generated_operations)
+        }
+        if let Some(known) = incarnation_of(&self.id) {
+            if self.incarnation < known {
+                // The member refuted this already
+                return Ok(generated_operations)
+            }
+        }
+        globals::INCARNATIONS.get_mut().insert(self.id.clone(), self.incarnation);
         let removed = remove_node(&self.id)?;
+        // Forgotten after a while, counting from when it actually left rather than from repeats of the news
+        let mut departed_at = globals::DEPARTED_AT.get_mut();
+        if removed || !departed_at.contains_key(&self.id) {
+            departed_at.insert(self.id.clone(), Instant::now());
+        }
+        drop(departed_at);
         if removed {
+            failure_detector::forget_member(&self.id);
             globals::UDP_TO_TCP_MAP.get_mut().remove(&self.id);
             generated_operations.push(SendableOperation::for_successors(Box::new(self.clone())));
             recalculate_neighbors()?;
@@ -378,12 +528,14 @@ impl OperationWriteExecute for LeaveOperation {
 
 impl OperationWriteExecute for NewMemberOperation {
     fn to_bytes(&self) -> BoxedErrorResult<Vec<u8>> {
-        Ok(create_buf(&self, str_to_vec("NMEM")))
+        Ok(create_buf(&self, str_to_vec(Self::TAG)))
     }
     fn execute(&self, _source: Source) -> BoxedErrorResult<Vec<SendableOperation>> {
-        insert_node(&self.id)?;
+        if !admit_member(&self.id, self.incarnation)? {
+            return Ok(vec![])
+        }
         globals::UDP_TO_TCP_MAP.get_mut().insert(ip_from_id(&self.id), self.tcp_addr.clone());
-        // MAYBE TODO: Do we need more redundancy to make sure joins are not missed?
+        globals::MEMBER_WEIGHTS.get_mut().insert(self.id.clone(), self.weight);
         recalculate_neighbors()?;
         Ok(vec![])
     }
@@ -392,13 +544,32 @@ impl OperationWriteExecute for NewMemberOperation {
 
 impl OperationWriteExecute for MemberInitializationOperation {
     fn to_bytes(&self) -> BoxedErrorResult<Vec<u8>> {
-        Ok(create_buf(&self, str_to_vec("MLIS")))
+        Ok(create_buf(&self, str_to_vec(Self::TAG)))
     }
     fn execute(&self, _source: Source) -> BoxedErrorResult<Vec<SendableOperation>> {
-        merge_membership_list(&self.membership_list)?;
+        globals::PROTOCOL_VERSION.write(self.protocol_version);
+        merge_membership_list(&self.membership_list, &self.incarnations)?;
         merge_tcp_map(&self.udp_to_tcp_map)?;
+        merge_member_weights(&self.member_weights)?;
+        merge_file_tombstones(&self.file_tombstones)?;
         merge_all_file_owners(&self.all_file_owners)?;
         recalculate_neighbors()?;
+        filesystem::announce_local_replicas()
+    }
+    fn to_string(&self) -> String { format!("{:?}", self) }
+}
+
+impl OperationWriteExecute for JoinRejectedOperation {
+    fn to_bytes(&self) -> BoxedErrorResult<Vec<u8>> {
+        Ok(create_buf(&self, str_to_vec(Self::TAG)))
+    }
+    fn execute(&self, _source: Source) -> BoxedErrorResult<Vec<SendableOperation>> {
+        // Nobody else knows about us yet, so just go back to not being joined
+        if is_joined() && globals::MEMBERSHIP_LIST.read().len() <= 1 {
+            clear_vars_on_leave()?;
+        }
+        println!("Join rejected: {}", self.reason);
+        log(format!("Join rejected: {}", self.reason))?;
         Ok(vec![])
     }
     fn to_string(&self) -> String { format!("{:?}", self) }
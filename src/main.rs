@@ -1,15 +1,24 @@
 #[macro_use]
 extern crate lazy_static;
+mod anti_entropy;
+mod auth;
 mod component_manager;
+mod config;
 mod constants;
 mod easyhash;
+mod erasure;
+mod failure_detector;
 mod filesystem;
 mod globals;
+mod hash_ring;
 mod heartbeat;
 mod locks;
 mod modular;
 mod operation;
+mod rpc;
+mod tls;
 use async_std;
+use config::Config;
 use std::{env, error, thread, time};
 use std::process::exit;
 use std::sync::{mpsc};
@@ -17,17 +26,24 @@ use std::sync::{mpsc};
 // Types
 pub type BoxedError = Box<dyn error::Error + Send + Sync>;
 pub type BoxedErrorResult<T> = std::result::Result<T, BoxedError>;
-type ArgResult = (u16);
+type ArgResult = Config;
 
 // Functions
 fn main() -> BoxedErrorResult<()> {
-    let port = parse_args_or_crash();
+    let config = parse_args_or_crash();
     let (operation_sender, operation_receiver) = mpsc::channel();
-    async_std::task::block_on(component_manager::startup(port))?;
-    component_manager::start_sender(Some(1000), operation_receiver);
+    async_std::task::block_on(component_manager::startup(config))?;
+    component_manager::start_sender(None, operation_receiver);
     component_manager::start_receiver(Some(1000), operation_sender.clone());
     component_manager::start_maintainer(Some(500), operation_sender.clone());
+    // Tick often enough that the ping timeout is honored to within a quarter of itself
+    let detector_interval = (globals::CONFIG.read().ping_timeout / 4).clamp(1, 50);
+    component_manager::start_failure_detector(Some(detector_interval), operation_sender.clone());
     component_manager::start_file_server(Some(500), operation_sender.clone());
+    let rebalance_interval = globals::CONFIG.read().rebalance_interval;
+    component_manager::start_rebalancer(Some(rebalance_interval), operation_sender.clone());
+    let gossip_interval = globals::CONFIG.read().gossip_interval;
+    component_manager::start_gossiper(Some(gossip_interval), operation_sender.clone());
     component_manager::start_console(None, operation_sender.clone());
     loop {
         thread::sleep(time::Duration::from_millis(1000));
@@ -35,6 +51,11 @@ fn main() -> BoxedErrorResult<()> {
 }
 
 fn parse_args_or_crash() -> ArgResult {
+    // Asking for help is not a mistake, so it neither needs valid flags nor fails
+    if env::args().skip(1).any(|arg| arg == "--help" || arg == "-h") {
+        help();
+        exit(0);
+    }
     match try_parse_args() {
         Ok(args) => args,
         Err(e)   => {
@@ -46,23 +67,72 @@ fn parse_args_or_crash() -> ArgResult {
 }
 
 fn try_parse_args() -> BoxedErrorResult<ArgResult> {
-    let args: Vec<String> = env::args().collect();
-    match args.len() {
-        2 => {
-            let port: u16 = args[1].parse()?;
-            Ok(port)
+    let args: Vec<String> = env::args().skip(1).collect();
+    // Load the config file first so that every other flag can override it
+    let mut config = match args.iter().position(|x| x == "--config") {
+        Some(idx) => {
+            let path = args.get(idx + 1).ok_or("Missing value for --config")?;
+            Config::from_file(path)?
         },
-        _ => Err(String::from("Incorrect number of arguments").into())
+        None => Config::default()
+    };
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        if arg.starts_with("--") {
+            let value = it.next().ok_or(format!("Missing value for {}", arg))?;
+            if arg != "--config" {
+                config.apply_flag(arg, value)?;
+            }
+        } else {
+            config.port = Some(arg.parse()?);
+        }
     }
+    config.validate()?;
+    Ok(config)
 }
 
 fn help() {
-    println!("Usage: ./BIN PORT_NUM");
+    println!("Usage: ./BIN [PORT_NUM] [--config PATH] [--FLAG VALUE]...");
+    println!("       ./BIN --help | -h");
+    println!("Flags (each overrides the same setting from the config file):");
+    println!("  --introducers ADDR[,ADDR...]  --port PORT         --tcp-port PORT");
+    println!("  --udp-addr ADDR               --tcp-addr ADDR     --advertise-udp-addr ADDR  --advertise-tcp-addr ADDR");
+    println!("  --log-dir DIR                 --data-dir DIR      --replicas N        --successors N");
+    println!("  --write-quorum N              --read-quorum N     --quorum-timeout MS");
+    println!("  --rpc-timeout MS              --rpc-retries N");
+    println!("  --probe-interval MS           --ping-timeout MS   --suspect-timeout MS       --indirect-probes N");
+    println!("  --gossip-interval MS          --virtual-nodes N   --weight N          --hash-algorithm xxhash64|sha256");
+    println!("  --chunk-size BYTES            --rebalance-interval MS                 --rebalance-batch-size N");
+    println!("  --max-frame-size BYTES        --departed-member-ttl MS                --file-tombstone-ttl MS");
+    println!("  --tls-ca-file PATH            --tls-cert-file PATH                    --tls-key-file PATH");
+    println!("  --udp-secret-file PATH        --udp-replay-window MS");
 }
 
 #[cfg(test)]
 mod tests {
+use crate::config::Config;
+use crate::constants;
+use crate::easyhash::{EasyHash, HashAlgorithm};
+use crate::erasure;
+use crate::filesystem;
+use crate::globals;
+use crate::hash_ring::HashRing;
+use crate::heartbeat;
+use crate::heartbeat::{JoinOperation, LeaveOperation};
 use crate::modular::*;
+use crate::operation::*;
+use crate::anti_entropy::MembershipDigestOperation;
+use crate::auth;
+use crate::rpc::RequestOperation;
+use crate::tls;
+use crate::constants::{HEADER_SIZE, MAX_UDP_FRAME_SIZE};
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+use async_std::prelude::*;
+use std::net::UdpSocket;
+use std::time::Duration;
+use std::collections::{HashMap, HashSet};
     #[test]
     fn modular_tests() {
         let m1 = Modular::new(1, 7);
@@ -78,4 +148,390 @@ use crate::modular::*;
         let m6 = Modular::new(5435, 1);
         assert_eq!(*m6, 0);
     }
+
+    #[test]
+    fn config_tests() {
+        let mut c1: Config = toml::from_str("introducers = [\"10.0.0.1:9000\"]\nnum_owners = 3\nport = 9000").unwrap();
+        assert_eq!(c1.introducers, vec!["10.0.0.1:9000"]);
+        assert_eq!(c1.num_owners, 3);
+        assert_eq!(c1.data_dir, Config::default().data_dir);
+        assert!(c1.validate().is_ok());
+        c1.apply_flag("--replicas", "5").unwrap();
+        c1.apply_flag("--introducers", "10.0.0.2:9000, 10.0.0.3:9000").unwrap();
+        assert_eq!(c1.num_owners, 5);
+        assert_eq!(c1.introducers, vec!["10.0.0.2:9000", "10.0.0.3:9000"]);
+        assert!(c1.apply_flag("--bogus", "1").is_err());
+        c1.apply_flag("--write-quorum", "6").unwrap();
+        assert!(c1.validate().is_err());
+        assert!(toml::from_str::<Config>("bogus = 1").is_err());
+        assert!(Config::default().validate().is_err());
+        let mut c2 = Config::default();
+        c2.apply_flag("--udp-addr", "[::1]:9000").unwrap();
+        assert!(c2.validate().is_ok());
+        c2.apply_flag("--advertise-tcp-addr", "::1:9003").unwrap();
+        assert!(c2.validate().is_err());
+        c2.apply_flag("--advertise-tcp-addr", "[::1]:9003").unwrap();
+        assert!(c2.validate().is_ok());
+        c2.apply_flag("--probe-interval", "200").unwrap();
+        assert!(c2.validate().is_err());
+        c2.apply_flag("--ping-timeout", "80").unwrap();
+        assert!(c2.validate().is_ok());
+        c2.apply_flag("--suspect-timeout", "0").unwrap();
+        assert!(c2.validate().is_err());
+        c2.apply_flag("--suspect-timeout", "1000").unwrap();
+        c2.apply_flag("--udp-secret-file", "udp.secret").unwrap();
+        assert!(c2.validate().is_err());
+        for (flag, file) in &[("--tls-ca-file", "ca.pem"), ("--tls-cert-file", "node.pem"), ("--tls-key-file", "node.key")] {
+            c2.apply_flag(flag, file).unwrap();
+        }
+        assert!(c2.validate().is_ok());
+        c2.apply_flag("--departed-member-ttl", "2000").unwrap();
+        assert!(c2.validate().is_err());
+        c2.apply_flag("--departed-member-ttl", "600000").unwrap();
+        c2.apply_flag("--file-tombstone-ttl", "2000").unwrap();
+        assert!(c2.validate().is_err());
+        c2.apply_flag("--file-tombstone-ttl", "3600000").unwrap();
+        assert!(c2.validate().is_ok());
+        c2.apply_flag("--weight", "65").unwrap();
+        assert!(c2.validate().is_err());
+    }
+
+    #[test]
+    fn hash_ring_tests() {
+        let members: Vec<String> = (0..4).map(|i| format!("10.0.0.{}:9000|0", i)).collect();
+        let files: Vec<String> = (0..2000).map(|i| format!("file{}", i)).collect();
+        let ring = HashRing::new(&members, &HashMap::new(), 64, HashAlgorithm::XxHash64);
+        let owners = ring.owners("file0", 3);
+        assert_eq!(owners.len(), 3);
+        assert_eq!(ring.owners("file0", 10).len(), 4);
+        assert_eq!(HashRing::new(&Vec::new(), &HashMap::new(), 64, HashAlgorithm::Sha256).owners("file0", 1).len(), 0);
+        // Adding a fifth member should only move about a fifth of the files
+        let mut grown_members = members.clone();
+        grown_members.push("10.0.0.4:9000|0".to_string());
+        let grown_ring = HashRing::new(&grown_members, &HashMap::new(), 64, HashAlgorithm::XxHash64);
+        let moved = files.iter().filter(|f| ring.owners(f, 1) != grown_ring.owners(f, 1)).count();
+        assert!(moved > 200 && moved < 700, "moved {} files", moved);
+        // A member with triple the weight should own about half of the files
+        let mut weights = HashMap::new();
+        weights.insert(members[0].clone(), 3);
+        let weighted_ring = HashRing::new(&members, &weights, 64, HashAlgorithm::XxHash64);
+        let heavy = files.iter().filter(|f| weighted_ring.owners(f, 1)[0] == members[0]).count();
+        assert!(heavy > 750 && heavy < 1250, "heavy member owns {} files", heavy);
+        // A weight off the wire is capped instead of overflowing or filling memory
+        weights.insert(members[0].clone(), u32::MAX);
+        let capped_ring = HashRing::new(&members, &weights, 64, HashAlgorithm::XxHash64);
+        assert_eq!(capped_ring.owners("file0", 4).len(), 4);
+    }
+
+    #[test]
+    fn easyhash_tests() {
+        // Pinned so that a dependency bump can't silently move every file
+        assert_eq!("".easyhash(HashAlgorithm::XxHash64), 0xEF46DB3751D8E999);
+        assert_eq!("abc".easyhash(HashAlgorithm::XxHash64), 0x44BC2CF5AD770999);
+        assert_eq!("".easyhash(HashAlgorithm::Sha256), 0xE3B0C44298FC1C14);
+        assert_eq!("abc".easyhash(HashAlgorithm::Sha256), 0xBA7816BF8F01CFEA);
+        assert_eq!(String::from("abc").easyhash(HashAlgorithm::Sha256), "abc".easyhash(HashAlgorithm::Sha256));
+        let c1: Config = toml::from_str("hash_algorithm = \"sha256\"").unwrap();
+        assert_eq!(c1.hash_algorithm, HashAlgorithm::Sha256);
+        assert!(toml::from_str::<Config>("hash_algorithm = \"default\"").is_err());
+        assert!(HashAlgorithm::from_name("md5").is_err());
+    }
+
+    #[test]
+    fn erasure_tests() {
+        let encode = |data: &[u8], info: &erasure::ShardInfo| {
+            let mut shards: Vec<Vec<u8>> = vec![Vec::new(); info.total_shards() as usize];
+            erasure::encode(&mut &data[..], data.len() as u64, info, &mut shards).unwrap();
+            shards
+        };
+        let decode = |shards: &Vec<Option<Vec<u8>>>, info: &erasure::ShardInfo| {
+            let shard_size = shards.iter().flatten().next().unwrap().len() as u64;
+            let inputs = shards.iter().map(|x| x.as_ref().map(|x| &x[..])).collect();
+            let mut data = Vec::new();
+            erasure::decode(inputs, shard_size, info, &mut data).map(|_| data)
+        };
+        let info = erasure::ShardInfo { parent: "file".to_string(), index: 0, data_shards: 4, parity_shards: 2, stripe_size: 64 };
+        let data: Vec<u8> = (0..1000).map(|i| (i * 7) as u8).collect();
+        let shards = encode(&data, &info);
+        assert_eq!(shards.len(), 6);
+        // Coded a stripe at a time, 64 bytes of every shard each
+        assert_eq!(shards[0].len(), 64 * 4);
+        // Any four of the six shards are enough
+        let mut partial: Vec<Option<Vec<u8>>> = shards.iter().cloned().map(Some).collect();
+        partial[0] = None;
+        partial[3] = None;
+        assert_eq!(decode(&partial, &info).unwrap(), data);
+        let mut rebuilt = Vec::new();
+        let inputs = partial.iter().map(|x| x.as_ref().map(|x| &x[..])).collect();
+        erasure::rebuild(inputs, shards[0].len() as u64, &info, 3, &mut rebuilt).unwrap();
+        assert_eq!(rebuilt, shards[3]);
+        partial[5] = None;
+        assert!(decode(&partial, &info).is_err());
+        // Small files only take up one shortened stripe
+        let small_info = erasure::ShardInfo { data_shards: 2, parity_shards: 1, ..info.clone() };
+        let empty = encode(&[], &small_info);
+        assert_eq!(empty[0].len(), 4);
+        assert_eq!(decode(&empty.into_iter().map(Some).collect(), &small_info).unwrap(), Vec::<u8>::new());
+        assert_eq!(erasure::parse_scheme("4+2").unwrap(), (4, 2));
+        assert!(erasure::parse_scheme("4").is_err());
+        assert!(erasure::parse_scheme("0+2").is_err());
+        assert!(filesystem::check_distributed_filename("file#0").is_err());
+        assert!(filesystem::check_distributed_filename("file@5").is_err());
+    }
+
+    #[test]
+    fn filesystem_tests() {
+        let parsed = |name: &str, version: Option<filesystem::Version>| (name.to_string(), version);
+        assert_eq!(filesystem::parse_versioned_filename("file@5"), parsed("file", Some(5)));
+        assert_eq!(filesystem::parse_versioned_filename("file@0"), parsed("file", Some(0)));
+        // Only the last '@' separates the version
+        assert_eq!(filesystem::parse_versioned_filename("a@1@2"), parsed("a@1", Some(2)));
+        // Anything that isn't a version stays part of the name
+        assert_eq!(filesystem::parse_versioned_filename("file"), parsed("file", None));
+        assert_eq!(filesystem::parse_versioned_filename("file@"), parsed("file@", None));
+        assert_eq!(filesystem::parse_versioned_filename("file@x"), parsed("file@x", None));
+        assert_eq!(filesystem::parse_versioned_filename("file@-1"), parsed("file@-1", None));
+        assert_eq!(filesystem::parse_versioned_filename("file@5.part"), parsed("file@5.part", None));
+        // The replication factor of the newest put wins, and an unknown one never replaces a known one
+        let mut metadata = filesystem::FileMetadata::new();
+        metadata.version = 3;
+        metadata.merge_replicas(0, 4);
+        assert_eq!(metadata.replicas, 0);
+        metadata.merge_replicas(2, 1);
+        assert_eq!(metadata.replicas, 2);
+        metadata.merge_replicas(5, 2);
+        metadata.merge_replicas(5, 3);
+        assert_eq!(metadata.replicas, 2);
+        metadata.merge_replicas(5, 4);
+        assert_eq!(metadata.replicas, 5);
+        metadata.merge_replicas(0, 9);
+        assert_eq!(metadata.replicas, 5);
+        // A tombstone hides every version up to the deleted one, whichever is merged first
+        globals::ALL_FILE_OWNERS.write(HashMap::new());
+        globals::FILE_TOMBSTONES.write(HashMap::new());
+        let owned = |version: filesystem::Version| {
+            let mut metadata = filesystem::FileMetadata::new();
+            metadata.version = version;
+            HashMap::from([("file".to_string(), metadata)])
+        };
+        let tombstone = |version: filesystem::Version| HashMap::from([("file".to_string(), version)]);
+        heartbeat::merge_all_file_owners(&owned(2)).unwrap();
+        heartbeat::merge_file_tombstones(&tombstone(3)).unwrap();
+        assert!(globals::ALL_FILE_OWNERS.read().is_empty());
+        heartbeat::merge_all_file_owners(&owned(3)).unwrap();
+        assert!(globals::ALL_FILE_OWNERS.read().is_empty());
+        // An older tombstone arriving late neither lowers the newer one nor hides a newer put
+        heartbeat::merge_file_tombstones(&tombstone(1)).unwrap();
+        assert_eq!(globals::FILE_TOMBSTONES.read()["file"], 3);
+        heartbeat::merge_all_file_owners(&owned(4)).unwrap();
+        assert_eq!(globals::ALL_FILE_OWNERS.read()["file"].version, 4);
+        heartbeat::merge_file_tombstones(&tombstone(4)).unwrap();
+        assert!(globals::ALL_FILE_OWNERS.read().is_empty());
+        // Tombstones are kept until every owner still around acked the delete, and then for the ttl
+        let ttl = Duration::from_millis(50);
+        let mut file_tombstones = HashMap::from([("a".to_string(), 1), ("b".to_string(), 1)]);
+        let mut tombstone_states = HashMap::from([
+            ("a".to_string(), filesystem::TombstoneState {
+                unacked_owners: HashSet::from(["m1".to_string(), "m2".to_string()]),
+                acked_at: None
+            }),
+            ("gone".to_string(), filesystem::TombstoneState::default())
+        ]);
+        let members = HashSet::from(["m1".to_string()]);
+        assert!(filesystem::remove_expired_tombstones(&mut file_tombstones, &mut tombstone_states, &members, ttl).is_empty());
+        // Owners that left don't hold it up, a state without a tombstone is dropped and one without a state
+        // starts waiting right away
+        assert_eq!(tombstone_states["a"].unacked_owners.len(), 1);
+        assert!(tombstone_states["a"].acked_at.is_none());
+        assert!(tombstone_states["b"].acked_at.is_some());
+        assert!(!tombstone_states.contains_key("gone"));
+        tombstone_states.get_mut("a").unwrap().unacked_owners.clear();
+        assert!(filesystem::remove_expired_tombstones(&mut file_tombstones, &mut tombstone_states, &members, ttl).is_empty());
+        assert!(tombstone_states["a"].acked_at.is_some());
+        std::thread::sleep(ttl);
+        let mut expired = filesystem::remove_expired_tombstones(&mut file_tombstones, &mut tombstone_states, &members, ttl);
+        expired.sort();
+        assert_eq!(expired, vec!["a".to_string(), "b".to_string()]);
+        assert!(file_tombstones.is_empty() && tombstone_states.is_empty());
+    }
+
+    #[test]
+    fn operation_registry_tests() {
+        globals::PROTOCOL_VERSION.write(constants::PROTOCOL_VERSION);
+        let leave = LeaveOperation { id: "127.0.0.1:9000|1".to_string(), incarnation: 3 };
+        let buf = leave.to_bytes().unwrap();
+        assert_eq!(vec_to_str(&buf), LeaveOperation::TAG);
+        assert_eq!(try_parse_buf(&buf).unwrap().to_string(), leave.to_string());
+        // Truncated, corrupted, foreign and headerless frames are errors instead of panics
+        assert!(try_parse_buf(&buf[..buf.len() - 1]).is_err());
+        let mut corrupted = buf.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        assert!(try_parse_buf(&corrupted).is_err());
+        assert!(try_parse_buf(&[b"XX".to_vec(), buf[2..].to_vec()].concat()).is_err());
+        assert!(try_parse_buf(b"DF").is_err());
+        // Frames from a newer protocol version are rejected
+        globals::PROTOCOL_VERSION.write(constants::PROTOCOL_VERSION + 1);
+        assert!(try_parse_buf(&leave.to_bytes().unwrap()).is_err());
+        // Joins are read at any version so that the introducer can say why it won't take the joiner
+        let join = JoinOperation {
+            id: "127.0.0.1:9000|1".to_string(),
+            tcp_addr: "127.0.0.1:9003".to_string(),
+            incarnation: 0,
+            weight: 1,
+            min_protocol_version: constants::PROTOCOL_VERSION + 1,
+            max_protocol_version: constants::PROTOCOL_VERSION + 1
+        };
+        assert!(try_parse_buf(&join.to_bytes().unwrap()).is_ok());
+        globals::PROTOCOL_VERSION.write(constants::PROTOCOL_VERSION);
+    }
+
+    // A fuzzing harness over the frame reader and every registered decoder. Mutated frames are resealed so that
+    // they get past the checksum and into the decoders, which must return errors instead of panicking or
+    // allocating whatever a length in the body claims. Frames are built at a fixed version rather than the
+    // global one, which other tests change.
+    #[test]
+    fn fuzz_frames() {
+        let mut rng = StdRng::seed_from_u64(425);
+        let seeds: Vec<Vec<u8>> = vec![
+            create_buf_at(&LeaveOperation { id: "127.0.0.1:9000|1".to_string(), incarnation: 3 },
+                          str_to_vec(LeaveOperation::TAG), constants::PROTOCOL_VERSION),
+            create_buf_at(&MembershipDigestOperation { versions: HashMap::new(), records: HashMap::new(), reply: true },
+                          str_to_vec(MembershipDigestOperation::TAG), constants::PROTOCOL_VERSION),
+            create_buf_at(&RequestOperation { request_id: 1, method: "stored_replicas".to_string(), payload: vec![] },
+                          str_to_vec(RequestOperation::TAG), constants::PROTOCOL_VERSION)
+        ];
+        let mut tags: Vec<&str> = OPERATION_DECODERS.keys().cloned().collect();
+        tags.sort();
+        for i in 0..20000 {
+            let mut buf = match i % 3 {
+                // Random bodies under every tag
+                0 => {
+                    let tag = tags[rng.gen_range(0, tags.len())];
+                    let mut buf = seeds[0][..HEADER_SIZE].to_vec();
+                    buf[4..8].copy_from_slice(tag.as_bytes());
+                    buf.extend((0..rng.gen_range(0, 64)).map(|_| rng.gen::<u8>()));
+                    buf
+                },
+                // Bit flips and truncations of real frames
+                _ => {
+                    let mut buf = seeds[rng.gen_range(0, seeds.len())].clone();
+                    for _ in 0..rng.gen_range(1, 4) {
+                        let idx = rng.gen_range(HEADER_SIZE, buf.len());
+                        buf[idx] ^= 1 << rng.gen_range(0, 8);
+                    }
+                    let len = rng.gen_range(HEADER_SIZE, buf.len() + 1);
+                    buf.truncate(len);
+                    buf
+                }
+            };
+            seal_frame(&mut buf);
+            let _ = try_parse_buf(&buf);
+            // Unsealed garbage, headers included
+            let garbage: Vec<u8> = (0..rng.gen_range(0, 40)).map(|_| rng.gen::<u8>()).collect();
+            assert!(try_parse_buf(&garbage).is_err());
+        }
+        // A length prefix far past the end of the body is refused rather than allocated
+        let mut buf = seeds[0][..HEADER_SIZE].to_vec();
+        buf.extend_from_slice(&u64::MAX.to_le_bytes());
+        seal_frame(&mut buf);
+        assert!(try_parse_buf(&buf).is_err());
+        // The same over a real socket, read into a buffer no bigger than a datagram gets, where every datagram is
+        // an error instead of blocking or panicking
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut oversized = seeds[0].clone();
+        oversized[HEADER_SIZE-8..HEADER_SIZE-4].copy_from_slice(&u32::MAX.to_le_bytes());
+        let datagrams = [vec![], b"DF".to_vec(), oversized, seeds[0][..seeds[0].len() - 1].to_vec()];
+        for datagram in datagrams.iter() {
+            sender.send_to(datagram, receiver.local_addr().unwrap()).unwrap();
+            let mut buf = vec![0; MAX_UDP_FRAME_SIZE];
+            let (num_received, _) = receiver.recv_from(&mut buf).unwrap();
+            buf.truncate(num_received);
+            assert!(try_parse_buf(&buf).is_err());
+        }
+    }
+
+    #[test]
+    fn auth_tests() {
+        let secret = b"a secret shared by the cluster";
+        let mut seen_nonces = auth::SeenNonces::default();
+        let frame = vec![7; HEADER_SIZE + 10];
+        let mut datagram = frame.clone();
+        auth::seal_with(&mut datagram, secret, 1000).unwrap();
+        assert_eq!(datagram.len(), frame.len() + constants::AUTH_TRAILER_SIZE);
+        // Anything tampered with, or sealed with another secret, is turned away
+        for idx in [0, frame.len(), datagram.len() - 1].iter() {
+            let mut tampered = datagram.clone();
+            tampered[*idx] ^= 1;
+            assert!(auth::open_with(&mut tampered, secret, 1000, 500, &mut seen_nonces).is_err());
+        }
+        assert!(auth::open_with(&mut datagram.clone(), b"another cluster's secret", 1000, 500, &mut seen_nonces).is_err());
+        assert!(auth::open_with(&mut frame.clone(), secret, 1000, 500, &mut seen_nonces).is_err());
+        // So is anything stamped outside the replay window either way
+        assert!(auth::open_with(&mut datagram.clone(), secret, 1501, 500, &mut seen_nonces).is_err());
+        assert!(auth::open_with(&mut datagram.clone(), secret, 499, 500, &mut seen_nonces).is_err());
+        // Within the window it is let through once, trailer stripped, and replays of it are not
+        let mut opened = datagram.clone();
+        auth::open_with(&mut opened, secret, 1200, 500, &mut seen_nonces).unwrap();
+        assert_eq!(opened, frame);
+        assert!(auth::open_with(&mut datagram.clone(), secret, 1300, 500, &mut seen_nonces).is_err());
+        // Nonces are forgotten once they can't pass the timestamp check anymore
+        let mut later = frame.clone();
+        auth::seal_with(&mut later, secret, 2000).unwrap();
+        auth::open_with(&mut later, secret, 2000, 500, &mut seen_nonces).unwrap();
+        assert_eq!(seen_nonces.stamps.len(), 1);
+        assert_eq!(seen_nonces.by_stamp.len(), 1);
+    }
+
+    #[test]
+    fn tls_tests() {
+        // A throwaway cluster CA and a certificate for a member on 127.0.0.1
+        let dir = std::env::temp_dir().join(format!("tls_tests_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+        let node_key = KeyPair::generate().unwrap();
+        let node = CertificateParams::new(vec!["127.0.0.1".to_string()]).unwrap().signed_by(&node_key, &ca, &ca_key).unwrap();
+        std::fs::write(path("ca.pem"), ca.pem()).unwrap();
+        std::fs::write(path("node.pem"), node.pem()).unwrap();
+        std::fs::write(path("node.key"), node_key.serialize_pem()).unwrap();
+
+        let mut config = Config::default();
+        assert!(tls::load(&config).unwrap().is_none());
+        config.tls_ca_file = Some(path("ca.pem"));
+        config.tls_cert_file = Some(path("node.pem"));
+        assert!(config.validate().is_err());
+        config.tls_key_file = Some(path("ca.pem"));
+        assert!(tls::load(&config).is_err());
+        config.tls_key_file = Some(path("node.key"));
+        globals::TLS.write(tls::load(&config).unwrap());
+
+        async_std::task::block_on(async {
+            let listener = async_std::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap().to_string();
+            let server = async_std::task::spawn(async move {
+                let (tcp, _) = listener.accept().await.unwrap();
+                let mut stream = tls::accept(tcp).await.unwrap();
+                let mut buf = vec![0; 5];
+                stream.read_exact(&mut buf).await.unwrap();
+                stream.write_frame(&buf).await.unwrap();
+                // Anyone without a certificate from the cluster CA is turned away during the handshake
+                let (tcp, _) = listener.accept().await.unwrap();
+                tls::accept(tcp).await.is_err()
+            });
+            let mut client = tls::connect(&addr).await.unwrap();
+            client.write_frame(b"hello").await.unwrap();
+            let mut buf = vec![0; 5];
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, b"hello");
+            let mut plain = async_std::net::TcpStream::connect(&addr).await.unwrap();
+            plain.write_all(b"hello over plain TCP").await.unwrap();
+            assert!(server.await);
+        });
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
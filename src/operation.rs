@@ -1,15 +1,21 @@
 use async_std;
 use async_std::io::ReadExt;
-use async_std::prelude::*;
 use async_trait::async_trait;
-use bincode;
+use bincode::{self, Options};
 use crate::{BoxedError, BoxedErrorResult};
+use crate::anti_entropy::MembershipDigestOperation;
+use crate::auth;
 use crate::component_manager::{log, OperationSender};
-use crate::constants::{HEADER_SIZE, OP_TYPE_SIZE};
-use crate::filesystem::{GetOperation, LostFilesOperation, NewFileOwnersOperation, SendFileOperation};
+use crate::constants::{self, HEADER_SIZE, MAGIC, MAX_UDP_FRAME_SIZE, OP_TYPE_SIZE};
+use crate::filesystem::{BadReplicaOperation, DeleteAckOperation, DeleteFileOperation, DiscardReplicaOperation, FileChunkOperation, GetOperation, LostFilesOperation, MoveReplicasOperation, NewFileOwnersOperation, SendFileOperation, WriteAckOperation};
 use crate::globals;
-use crate::heartbeat::{ips_from_ids, HeartbeatOperation, JoinOperation, LeaveOperation, NewMemberOperation, MemberInitializationOperation, self};
-use serde::{Serialize};
+use crate::failure_detector::{AckOperation, PingOperation, PingReqOperation};
+use crate::heartbeat::{ips_from_ids, JoinOperation, JoinRejectedOperation, LeaveOperation, NewMemberOperation, MemberInitializationOperation, self};
+use crate::rpc::{RequestOperation, ResponseOperation};
+use crate::tls::{self, ClusterStream};
+use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
 use std::convert::TryInto;
 use std::fmt::Debug;
 use std::net::UdpSocket;
@@ -20,7 +26,7 @@ type BoxedOperation = Box<dyn OperationWriteExecute + Send + Sync>;
 pub enum Destinations {
     UDPAddr(Vec<String>),
     TCPAddr(Vec<String>),
-    TCPStream(Vec<async_std::net::TcpStream>)
+    TCPStream(Vec<ClusterStream>)
 }
 
 // Operation Queue Item
@@ -36,15 +42,35 @@ impl SendableOperation {
             Destinations::UDPAddr(dests) => dests,
             _ => return Err("Cannot send UDP packets to TCP destinations".into())
         };
-        let serialized = self.operation.to_bytes()?;
+        let mut serialized = self.operation.to_bytes()?;
+        if auth::sealed_size(serialized.len()) > MAX_UDP_FRAME_SIZE {
+            // Too big for one datagram, so it goes over TCP to the same members and is executed there all the same,
+            // with our UDP address as its source. Spawned so that the probes and acks queued up behind it aren't
+            // held up by the connections.
+            let relayed = UdpFallbackOperation {
+                source: globals::MY_IP_ADDR.read().clone(),
+                frame: serialized
+            }.to_bytes()?;
+            for udp_dest in dests.clone() {
+                let relayed = relayed.clone();
+                async_std::task::spawn(async move {
+                    if let Err(e) = write_frame_over_tcp(&udp_dest, &relayed).await {
+                        let _ = log(format!("Failed to send a {} byte frame to {} over TCP: {}", relayed.len(), udp_dest, e));
+                    }
+                });
+            }
+            log(format!("Sent a {} to {:?} over TCP since it is {} bytes", self.operation.to_string(), dests, relayed.len()))?;
+            return Ok(())
+        }
+        auth::seal(&mut serialized)?;
         for udp_dest in &dests {
             socket.send_to(&serialized, &udp_dest)?;
         }
         log(format!("Sent a {} to {:?}", self.operation.to_string(), dests));
         Ok(())
     }
-    pub async fn write_all_tcp_async(self) -> BoxedErrorResult<Vec<async_std::net::TcpStream>> {
-        let streams = match self.dests {
+    pub async fn write_all_tcp_async(self) -> BoxedErrorResult<Vec<ClusterStream>> {
+        let mut streams = match self.dests {
             Destinations::UDPAddr(dests) => {
                 let tcp_map = globals::UDP_TO_TCP_MAP.read();
                 let dests = heartbeat::tcp_ips_from_udp_ips(&dests)?;
@@ -57,13 +83,13 @@ impl SendableOperation {
         };
         let serialized = self.operation.to_bytes()?;
         // TODO: Parallelize
-        for mut stream in &streams {
-            stream.write_all(&serialized).await?;
+        for stream in streams.iter_mut() {
+            stream.write_frame(&serialized).await?;
         }
-        // I don't like this unwrap but whatever
         let dests: Vec<String> = streams
             .iter()
-            .map(|x| x.peer_addr().unwrap().to_string())
+            .filter_map(|x| x.peer_addr().ok())
+            .map(|x| x.to_string())
             .collect();
         log(format!("Sent a {} to {:?}", self.operation.to_string(), dests));
         Ok(streams)
@@ -74,13 +100,13 @@ impl SendableOperation {
             operation: operation
         }
     }
-    pub fn for_tcp_stream_list(streams: Vec<async_std::net::TcpStream>, operation: BoxedOperation) -> Self {
+    pub fn for_tcp_stream_list(streams: Vec<ClusterStream>, operation: BoxedOperation) -> Self {
         SendableOperation {
             dests: Destinations::TCPStream(streams),
             operation: operation
         }
     }
-    pub fn for_single_tcp_stream(stream: async_std::net::TcpStream, operation: BoxedOperation) -> Self {
+    pub fn for_single_tcp_stream(stream: ClusterStream, operation: BoxedOperation) -> Self {
         Self::for_tcp_stream_list(vec![stream], operation)
     }
     pub fn for_everyone(operation: BoxedOperation) -> Self {
@@ -94,8 +120,8 @@ impl SendableOperation {
     }
     pub fn for_owners(filename: &String, operation: BoxedOperation) -> Self {
         let id_list: Vec<String> = match globals::ALL_FILE_OWNERS.read().get(filename) {
-            Some(owners) => {
-                owners.iter()
+            Some(metadata) => {
+                metadata.owners.iter()
                       .map(|x| x.to_string())
                       .collect()
             },
@@ -110,7 +136,7 @@ impl SendableOperation {
 #[derive(Debug, Clone)]
 pub enum Source {
     Addr(String),
-    TcpStream(async_std::net::TcpStream)
+    TcpStream(ClusterStream)
 }
 
 impl TryInto<String> for Source {
@@ -123,9 +149,9 @@ impl TryInto<String> for Source {
     }
 }
 
-impl TryInto<async_std::net::TcpStream> for Source {
+impl TryInto<ClusterStream> for Source {
     type Error = BoxedError;
-    fn try_into(self) -> BoxedErrorResult<async_std::net::TcpStream> {
+    fn try_into(self) -> BoxedErrorResult<ClusterStream> {
         match self {
             Source::Addr(_) => Err("Cannot translate address into TcpStream".into()),
             Source::TcpStream(stream) => Ok(stream)
@@ -140,6 +166,130 @@ impl Source {
 }
 
 
+// Registry
+// Every operation that can be read off a socket and the tag it is framed with. This list is the only place a tag
+// is spelled out, so adding an operation is one line here, and a repeated tag or type fails to compile.
+macro_rules! register_operations {
+    ($($tag:literal => $operation:ty),* $(,)?) => {
+        $(
+            impl TaggedOperation for $operation {
+                const TAG: &'static str = $tag;
+            }
+        )*
+        const _: () = assert!(tags_are_valid(&[$($tag),*]), "Operation tags must be unique and exactly OP_TYPE_SIZE bytes");
+        lazy_static! {
+            pub static ref OPERATION_DECODERS: HashMap<&'static str, OperationDecoder> = {
+                let mut decoders: HashMap<&'static str, OperationDecoder> = HashMap::new();
+                $(decoders.insert($tag, decode_operation::<$operation>);)*
+                decoders
+            };
+        }
+    };
+}
+
+register_operations! {
+    "PING" => PingOperation,
+    "PREQ" => PingReqOperation,
+    "ACK " => AckOperation,
+    "JOIN" => JoinOperation,
+    "JREJ" => JoinRejectedOperation,
+    "LEAV" => LeaveOperation,
+    "NMEM" => NewMemberOperation,
+    "MLIS" => MemberInitializationOperation,
+    "MDIG" => MembershipDigestOperation,
+    "GET " => GetOperation,
+    "NFO " => NewFileOwnersOperation,
+    "FILE" => SendFileOperation,
+    "CHNK" => FileChunkOperation,
+    "WACK" => WriteAckOperation,
+    "BADR" => BadReplicaOperation,
+    "DISC" => DiscardReplicaOperation,
+    "LOST" => LostFilesOperation,
+    "DEL " => DeleteFileOperation,
+    "DACK" => DeleteAckOperation,
+    "MOVE" => MoveReplicasOperation,
+    "RQST" => RequestOperation,
+    "RESP" => ResponseOperation,
+    "UDPF" => UdpFallbackOperation,
+}
+
+// Operations that are only ever sent over TCP, either because they are answered on the same stream or because
+// they carry file data. Read off UDP they could only fail, or let a forged datagram in past TLS.
+const TCP_ONLY_TAGS: [&str; 9] = [
+    GetOperation::TAG,
+    SendFileOperation::TAG,
+    FileChunkOperation::TAG,
+    WriteAckOperation::TAG,
+    BadReplicaOperation::TAG,
+    DiscardReplicaOperation::TAG,
+    RequestOperation::TAG,
+    ResponseOperation::TAG,
+    UdpFallbackOperation::TAG
+];
+
+pub type OperationDecoder = fn(&[u8]) -> BoxedErrorResult<BoxedOperation>;
+
+fn decode_operation<T>(body: &[u8]) -> BoxedErrorResult<BoxedOperation>
+where T: TaggedOperation {
+    Ok(Box::new(decode_body::<T>(body)?))
+}
+
+// Same encoding as bincode::deserialize, except that no length read out of the body can make it allocate more than
+// the body itself holds
+pub fn decode_body<T>(body: &[u8]) -> BoxedErrorResult<T>
+where T: DeserializeOwned {
+    Ok(bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(body.len() as u64)
+        .deserialize::<T>(body)?)
+}
+
+const fn tags_are_valid(tags: &[&str]) -> bool {
+    let mut i = 0;
+    while i < tags.len() {
+        if tags[i].len() != 4 {
+            return false;
+        }
+        let mut j = i + 1;
+        while j < tags.len() {
+            let (a, b) = (tags[i].as_bytes(), tags[j].as_bytes());
+            if a[0] == b[0] && a[1] == b[1] && a[2] == b[2] && a[3] == b[3] {
+                return false;
+            }
+            j += 1;
+        }
+        i += 1;
+    }
+    true
+}
+
+// Operations
+// An operation meant for UDP that was too big for a datagram and came over TCP instead, along with the UDP address
+// of the member that sent it, so that it executes exactly as if it had arrived as a datagram
+#[derive(Serialize, Deserialize, Clone)]
+pub struct UdpFallbackOperation {
+    pub source: String,
+    pub frame: Vec<u8>
+}
+
+impl OperationWriteExecute for UdpFallbackOperation {
+    fn to_bytes(&self) -> BoxedErrorResult<Vec<u8>> {
+        Ok(create_buf(&self, str_to_vec(Self::TAG)))
+    }
+    fn execute(&self, _source: Source) -> BoxedErrorResult<Vec<SendableOperation>> {
+        let operation = try_parse_buf(&self.frame)?;
+        if vec_to_str(&self.frame) == Self::TAG {
+            return Err("Cannot relay a relayed operation".into())
+        }
+        log(format!("Read a {} relayed over TCP from {}", operation.to_string(), self.source))?;
+        operation.execute(Source::Addr(self.source.clone()))
+    }
+    fn to_string(&self) -> String {
+        format!("UdpFallbackOperation {{ source: {:?}, frame: {} bytes }}", self.source, self.frame.len())
+    }
+}
+
 // Traits
 pub trait OperationWriteExecute {
     fn to_bytes(&self) -> BoxedErrorResult<Vec<u8>>;
@@ -148,14 +298,93 @@ pub trait OperationWriteExecute {
     fn to_string(&self) -> String;
 }
 
+// Given to every registered operation by register_operations - not object safe, so kept apart from OperationWriteExecute
+pub trait TaggedOperation: OperationWriteExecute + DeserializeOwned + Send + Sync + 'static {
+    const TAG: &'static str;
+}
+
 // Functions
-pub fn create_buf<T>(obj: &T, mut base: Vec<u8>) -> Vec<u8>
+async fn write_frame_over_tcp(udp_dest: &str, serialized: &[u8]) -> BoxedErrorResult<()> {
+    let tcp_dest = heartbeat::tcp_ips_from_udp_ips(&vec![udp_dest.to_string()])?.remove(0);
+    let mut stream = tls::connect(&tcp_dest).await?;
+    stream.write_frame(serialized).await?;
+    Ok(())
+}
+
+// Frames the operation with the header described in constants.rs, written at the version agreed on with the cluster
+pub fn create_buf<T>(obj: &T, tag: Vec<u8>) -> Vec<u8>
+where T: Serialize {
+    let version = *globals::PROTOCOL_VERSION.read();
+    create_buf_at(obj, tag, version)
+}
+
+pub fn create_buf_at<T>(obj: &T, tag: Vec<u8>, version: u16) -> Vec<u8>
 where T: Serialize {
     let serialized = bincode::serialize(obj).unwrap();
-    let size: u32 = (HEADER_SIZE + serialized.len()) as u32;
-    base.extend_from_slice(&size.to_le_bytes());
-    base.extend_from_slice(&serialized);
-    return base;
+    let mut buf: Vec<u8> = Vec::with_capacity(HEADER_SIZE + serialized.len());
+    buf.extend_from_slice(&MAGIC);
+    buf.extend_from_slice(&version.to_le_bytes());
+    buf.extend_from_slice(&tag);
+    buf.extend_from_slice(&[0; 8]);
+    buf.extend_from_slice(&serialized);
+    seal_frame(&mut buf);
+    buf
+}
+
+// Fills in the size and checksum of a frame whose header is otherwise written
+pub fn seal_frame(buf: &mut [u8]) {
+    let size = buf.len() as u32;
+    buf[HEADER_SIZE-8..HEADER_SIZE-4].copy_from_slice(&size.to_le_bytes());
+    let crc = frame_crc(buf);
+    buf[HEADER_SIZE-4..HEADER_SIZE].copy_from_slice(&crc.to_le_bytes());
+}
+
+// Covers the whole frame except the CRC field itself
+fn frame_crc(buf: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&buf[..HEADER_SIZE-4]);
+    hasher.update(&buf[HEADER_SIZE..]);
+    hasher.finalize()
+}
+
+pub fn frame_version(header: &[u8]) -> u16 {
+    u16::from_le_bytes([header[2], header[3]])
+}
+
+// The size the header claims the whole frame has, after making sure it is one of our frames at all
+pub fn frame_size(header: &[u8]) -> BoxedErrorResult<usize> {
+    if header.len() < HEADER_SIZE {
+        return Err(format!("Read {} bytes which is smaller than a header", header.len()).into())
+    }
+    if header[..2] != MAGIC {
+        return Err(format!("Read a frame with bad magic {:?}", &header[..2]).into())
+    }
+    let size = u32::from_le_bytes(header[4+OP_TYPE_SIZE..8+OP_TYPE_SIZE].try_into()?) as usize;
+    if size < HEADER_SIZE {
+        return Err(format!("Read a frame of size {} which is smaller than its header", size).into())
+    }
+    Ok(size)
+}
+
+// Checks a complete frame before anything in it is trusted. Joins and their rejections keep the same layout in
+// every version, so they are read whatever version they were written at - that is how mismatched nodes find out.
+pub fn check_frame(buf: &[u8]) -> BoxedErrorResult<()> {
+    let size = frame_size(buf)?;
+    if size != buf.len() {
+        return Err(format!("Read a frame of {} bytes but its header claims {}", buf.len(), size).into())
+    }
+    let crc = u32::from_le_bytes(buf[HEADER_SIZE-4..HEADER_SIZE].try_into()?);
+    if crc != frame_crc(buf) {
+        return Err(format!("Read a {:?} frame with a bad checksum", vec_to_str(buf)).into())
+    }
+    let version = frame_version(buf);
+    let tag = vec_to_str(buf);
+    let is_handshake = tag == JoinOperation::TAG || tag == JoinRejectedOperation::TAG;
+    if !is_handshake && (version < constants::MIN_PROTOCOL_VERSION || version > constants::PROTOCOL_VERSION) {
+        return Err(format!("Read a {:?} frame at protocol version {} but only {} to {} are supported",
+                           tag, version, constants::MIN_PROTOCOL_VERSION, constants::PROTOCOL_VERSION).into())
+    }
+    Ok(())
 }
 
 pub trait TryReadOperation {
@@ -172,66 +401,86 @@ pub fn str_to_vec(s: &str) -> Vec<u8> {
     s.chars().map(|c| c as u8).collect()
 }
 
-pub fn vec_to_str(v: &Vec<u8>) -> String {
-    v[..OP_TYPE_SIZE].iter().map(|b| b.clone() as char).collect::<String>()
+// The tag of a frame
+pub fn vec_to_str(v: &[u8]) -> String {
+    v[4..4+OP_TYPE_SIZE].iter().map(|b| *b as char).collect::<String>()
 }
 
 // Might wanna move this one
-async fn connect_via_tcp(dests: Vec<String>) -> BoxedErrorResult<Vec<async_std::net::TcpStream>> {
-    let mut streams: Vec<async_std::net::TcpStream> = Vec::new();
+async fn connect_via_tcp(dests: Vec<String>) -> BoxedErrorResult<Vec<ClusterStream>> {
+    let mut streams: Vec<ClusterStream> = Vec::new();
     for dest in &dests {
-        let mut stream = async_std::net::TcpStream::connect(dest).await?;
-        streams.push(stream.clone());
+        // Unreachable destinations are skipped so the caller can decide whether enough of them answered
+        match tls::connect(dest).await {
+            Ok(stream) => streams.push(stream),
+            Err(e) => {
+                log(format!("Could not connect to {}: {}", dest, e))?;
+            }
+        }
     }
     Ok(streams)
 }
 
-fn try_parse_buf(buf: &Vec<u8>) -> BoxedErrorResult<BoxedOperation> {
-    let operation: BoxedOperation = match vec_to_str(&buf).as_str() {
-        "HB  " => Box::new(bincode::deserialize::<HeartbeatOperation>(&buf[HEADER_SIZE..]).unwrap()),
-        "JOIN" => Box::new(bincode::deserialize::<JoinOperation>(&buf[HEADER_SIZE..]).unwrap()),
-        "LEAV" => Box::new(bincode::deserialize::<LeaveOperation>(&buf[HEADER_SIZE..]).unwrap()),
-        "NMEM" => Box::new(bincode::deserialize::<NewMemberOperation>(&buf[HEADER_SIZE..]).unwrap()),
-        "MLIS" => Box::new(bincode::deserialize::<MemberInitializationOperation>(&buf[HEADER_SIZE..]).unwrap()),
-        "GET " => Box::new(bincode::deserialize::<GetOperation>(&buf[HEADER_SIZE..]).unwrap()),
-        "NFO " => Box::new(bincode::deserialize::<NewFileOwnersOperation>(&buf[HEADER_SIZE..]).unwrap()),
-        "FILE" => Box::new(bincode::deserialize::<SendFileOperation>(&buf[HEADER_SIZE..]).unwrap()),
-        "LOST" => Box::new(bincode::deserialize::<LostFilesOperation>(&buf[HEADER_SIZE..]).unwrap()),
-        _   => return Err(String::from("Read unrecognized operation header").into())
-    };
-    Ok(operation)
+// For replies the caller is waiting on, where the type of the operation is known ahead of time
+pub async fn read_typed_operation<T>(stream: &mut ClusterStream) -> BoxedErrorResult<T>
+where T: TaggedOperation {
+    let buf = read_buf_async(stream).await?;
+    check_frame(&buf)?;
+    let read_op_type = vec_to_str(&buf);
+    if read_op_type != T::TAG {
+        return Err(format!("Expected a {:?} operation but read a {:?}", T::TAG, read_op_type).into())
+    }
+    decode_body::<T>(&buf[HEADER_SIZE..])
+}
+
+async fn read_buf_async(stream: &mut ClusterStream) -> BoxedErrorResult<Vec<u8>> {
+    // Parse the header - read rather than peeked so that a closed stream is an error instead of an empty buf
+    let mut buf: Vec<u8> = vec![0; HEADER_SIZE];
+    stream.read_exact(&mut buf).await?;
+    let buf_size = frame_size(&buf)?;
+    // The size comes off the wire, so it is bounded before anything gets allocated for it
+    let max_frame_size = globals::CONFIG.read().max_frame_size;
+    if buf_size > max_frame_size {
+        return Err(format!("Read a {:?} frame of {} bytes which is over the limit of {}", vec_to_str(&buf), buf_size, max_frame_size).into())
+    }
+    buf.resize(buf_size, 0);
+    stream.read_exact(&mut buf[HEADER_SIZE..]).await?;
+    Ok(buf)
+}
+
+// Looks the tag up in the registry - a frame that is unknown or doesn't decode is an error for the caller to log
+pub fn try_parse_buf(buf: &[u8]) -> BoxedErrorResult<BoxedOperation> {
+    check_frame(buf)?;
+    let op_type = vec_to_str(buf);
+    let decode = OPERATION_DECODERS.get(op_type.as_str()).ok_or(format!("Read unrecognized operation header {:?}", op_type))?;
+    decode(&buf[HEADER_SIZE..]).map_err(|e| format!("Could not decode a {:?} operation: {}", op_type, e).into())
 }
 
 impl TryReadOperation for UdpSocket {
     fn try_read_operation(&self) -> BoxedErrorResult<(BoxedOperation, Source)> {
-        // Parse the header
-        let mut header: Vec<u8> = vec![0; HEADER_SIZE];
-        let _ = self.peek_from(&mut header).expect("Read called on an empty UDP socket.");
-        let buf_size: u32 = u32::from_le_bytes(header[OP_TYPE_SIZE..OP_TYPE_SIZE+4].try_into()?);
-        // Receive the full message - TODO: Some assertions on the buf_size before creating the vec?
-        let mut buf: Vec<u8> = vec![0; buf_size as usize];
-        let (_, sender) = self.recv_from(&mut buf)
-            .expect("Read called on an empty UDP socket.");
+        // A datagram can't be bigger than this, so the size in the header is never trusted for the allocation.
+        // Anything longer is cut off and then fails the size check.
+        let mut buf: Vec<u8> = vec![0; MAX_UDP_FRAME_SIZE];
+        let (num_received, sender) = self.recv_from(&mut buf)?;
+        buf.truncate(num_received);
+        auth::open(&mut buf).map_err(|e| format!("Dropped an unauthenticated frame from {}: {}", sender, e))?;
         // Create the correct operation
-        let operation = try_parse_buf(&buf)?;
+        let operation = try_parse_buf(&buf).map_err(|e| format!("Dropped a frame from {}: {}", sender, e))?;
+        if TCP_ONLY_TAGS.contains(&vec_to_str(&buf).as_str()) {
+            return Err(format!("Dropped a {} from {} since it is only accepted over TCP", operation.to_string(), sender).into())
+        }
         log(format!("Read a {} from {:?}", operation.to_string(), &sender));
         return Ok((operation, Source::Addr(sender.to_string())));
     }
 }
 
 #[async_trait]
-impl TryReadOperationAsync for async_std::net::TcpStream {
+impl TryReadOperationAsync for ClusterStream {
     async fn try_read_operation(&mut self) -> BoxedErrorResult<(BoxedOperation, Source)> {
-        // Parse the header
-        let mut header: Vec<u8> = vec![0; HEADER_SIZE];
-        let _ = self.peek(&mut header).await.expect("Read called on an empty TcpStream");
-        let buf_size: usize = u32::from_le_bytes(header[OP_TYPE_SIZE..OP_TYPE_SIZE+4].try_into()?) as usize;
-        // Receive the full message - TODO: Some assertions on the buf_size before creating the vec?
-        let mut buf: Vec<u8> = vec![0; buf_size];
-        self.read_exact(&mut buf).await?;
-        // Create the correct operation
-        let operation = try_parse_buf(&buf)?;
         let sender = self.peer_addr()?;
+        let buf = read_buf_async(self).await.map_err(|e| format!("Dropped a frame from {}: {}", sender, e))?;
+        // Create the correct operation
+        let operation = try_parse_buf(&buf).map_err(|e| format!("Dropped a frame from {}: {}", sender, e))?;
         log(format!("Read a {} from {:?}", operation.to_string(), &sender));
         return Ok((operation, Source::TcpStream(self.clone())));
     }
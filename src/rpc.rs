@@ -0,0 +1,164 @@
+use crate::BoxedErrorResult;
+use crate::component_manager::log;
+use crate::constants;
+use crate::filesystem::{NextVersionRequest, StoredReplicasRequest};
+use crate::globals;
+use crate::heartbeat;
+use crate::operation::*;
+use crate::tls::{self, ClusterStream};
+use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
+use std::convert::TryInto;
+use std::time::Duration;
+
+// Request/response calls between members over the TCP channel. The request is wrapped in a RequestOperation
+// along with a fresh id and its method name, the callee runs the handler registered for that method and
+// writes back a ResponseOperation with the same id holding either the typed response or the error.
+
+pub type RequestId = u64;
+
+// Implemented by every request that can be sent with call - the response comes back already deserialized
+pub trait RpcRequest: Serialize + DeserializeOwned {
+    type Response: Serialize + DeserializeOwned;
+    // Picks the handler on the callee, so it has to be unique and listed in dispatch
+    const METHOD: &'static str;
+    // Whether running the handler twice is harmless - only then is a call retried, since a timed out attempt may
+    // still have run on the callee
+    const IDEMPOTENT: bool = true;
+    fn handle(&self, source: &Source) -> BoxedErrorResult<Self::Response>;
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CallOptions {
+    // Covers connecting, sending and waiting on the response of a single attempt
+    pub timeout: Duration,
+    // Attempts after the first one - only for timeouts and connection errors of idempotent requests, never for errors
+    // from the handler
+    pub retries: u32
+}
+
+impl Default for CallOptions {
+    fn default() -> Self {
+        let config = globals::CONFIG.read();
+        CallOptions {
+            timeout: Duration::from_millis(config.rpc_timeout),
+            retries: config.rpc_retries
+        }
+    }
+}
+
+pub fn call<R: RpcRequest>(dest_id: &String, request: &R) -> BoxedErrorResult<R::Response> {
+    call_with_options(dest_id, request, CallOptions::default())
+}
+
+pub fn call_with_options<R: RpcRequest>(dest_id: &String, request: &R, options: CallOptions) -> BoxedErrorResult<R::Response> {
+    if globals::MEMBERSHIP_LIST.read().binary_search(dest_id).is_err() {
+        return Err(format!("{} is not a member", dest_id).into())
+    }
+    let tcp_addr = heartbeat::tcp_ips_from_ids(&vec![dest_id.clone()])?.remove(0);
+    let payload = bincode::serialize(request)?;
+    let retries = match R::IDEMPOTENT {
+        true  => options.retries,
+        false => 0
+    };
+    let mut last_error = String::new();
+    for attempt in 0..=retries {
+        if attempt > 0 {
+            let backoff = constants::RPC_RETRY_BACKOFF << (attempt - 1);
+            async_std::task::block_on(async_std::task::sleep(Duration::from_millis(backoff)));
+        }
+        let request_id = next_request_id();
+        let attempt_result = async_std::task::block_on(
+            async_std::future::timeout(options.timeout, call_once(&tcp_addr, request_id, R::METHOD, &payload))
+        );
+        match attempt_result {
+            Ok(Ok(Ok(response))) => return decode_body(&response),
+            Ok(Ok(Err(e))) => return Err(format!("{} failed on {}: {}", R::METHOD, dest_id, e).into()),
+            Ok(Err(e)) => last_error = e.to_string(),
+            Err(_) => last_error = format!("timed out after {:?}", options.timeout)
+        }
+        log(format!("Call {} #{} to {} failed on attempt {}/{}: {}",
+                    R::METHOD, request_id, dest_id, attempt + 1, retries + 1, last_error))?;
+    }
+    Err(format!("{} to {} failed after {} attempts: {}", R::METHOD, dest_id, retries + 1, last_error).into())
+}
+
+// The outer error is the transport failing, the inner one is what the handler on the callee returned
+async fn call_once(tcp_addr: &String, request_id: RequestId, method: &str, payload: &[u8]) -> BoxedErrorResult<Result<Vec<u8>, String>> {
+    let mut stream = tls::connect(tcp_addr).await?;
+    SendableOperation::for_single_tcp_stream(stream.clone(), Box::new(RequestOperation {
+        request_id,
+        method: method.to_string(),
+        payload: payload.to_owned()
+    })).write_all_tcp_async().await?;
+    let response = read_typed_operation::<ResponseOperation>(&mut stream).await?;
+    if response.request_id != request_id {
+        return Err(format!("Expected the response to request #{} but got #{}", request_id, response.request_id).into())
+    }
+    Ok(response.result)
+}
+
+fn next_request_id() -> RequestId {
+    let mut next_request_id = globals::NEXT_REQUEST_ID.get_mut();
+    *next_request_id += 1;
+    *next_request_id
+}
+
+fn dispatch(method: &str, payload: &[u8], source: &Source) -> BoxedErrorResult<Vec<u8>> {
+    match method {
+        StoredReplicasRequest::METHOD => handle::<StoredReplicasRequest>(payload, source),
+        NextVersionRequest::METHOD => handle::<NextVersionRequest>(payload, source),
+        _ => Err(format!("Unknown method {}", method).into())
+    }
+}
+
+fn handle<R: RpcRequest>(payload: &[u8], source: &Source) -> BoxedErrorResult<Vec<u8>> {
+    let request: R = decode_body(payload)?;
+    Ok(bincode::serialize(&request.handle(source)?)?)
+}
+
+// Operations
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RequestOperation {
+    pub request_id: RequestId,
+    pub method: String,
+    pub payload: Vec<u8>
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ResponseOperation {
+    pub request_id: RequestId,
+    pub result: Result<Vec<u8>, String>
+}
+
+// Trait Impls
+impl OperationWriteExecute for RequestOperation {
+    fn to_bytes(&self) -> BoxedErrorResult<Vec<u8>> {
+        Ok(create_buf(&self, str_to_vec(Self::TAG)))
+    }
+    fn execute(&self, source: Source) -> BoxedErrorResult<Vec<SendableOperation>> {
+        let result = dispatch(&self.method, &self.payload, &source).map_err(|e| e.to_string());
+        if let Err(e) = &result {
+            log(format!("Request {} #{} failed: {}", self.method, self.request_id, e))?;
+        }
+        let stream = TryInto::<ClusterStream>::try_into(source)?;
+        let response = SendableOperation::for_single_tcp_stream(stream, Box::new(ResponseOperation {
+            request_id: self.request_id,
+            result
+        }));
+        async_std::task::block_on(response.write_all_tcp_async())?;
+        Ok(vec![])
+    }
+    fn to_string(&self) -> String { format!("{:?}", self) }
+}
+
+impl OperationWriteExecute for ResponseOperation {
+    fn to_bytes(&self) -> BoxedErrorResult<Vec<u8>> {
+        Ok(create_buf(&self, str_to_vec(Self::TAG)))
+    }
+    fn execute(&self, _source: Source) -> BoxedErrorResult<Vec<SendableOperation>> {
+        // Responses are read directly by the caller waiting on them, so a stray one has nothing left to do
+        Ok(vec![])
+    }
+    fn to_string(&self) -> String { format!("{:?}", self) }
+}
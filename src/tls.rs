@@ -0,0 +1,168 @@
+use async_std::io::{Read, Write};
+use async_std::net::TcpStream;
+use async_std::prelude::*;
+use crate::BoxedErrorResult;
+use crate::config::Config;
+use crate::globals;
+use futures_rustls::{TlsAcceptor, TlsConnector, TlsStream};
+use futures_rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use futures_rustls::rustls::{self, ClientConfig, RootCertStore, ServerConfig};
+use futures_rustls::rustls::server::WebPkiClientVerifier;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+// Mutual TLS for the TCP channel. Every member holds a certificate signed by the cluster CA and both ends of a
+// connection have to present one, so only members can send files, fetch them or make calls. Without the
+// tls_*_file options connections stay plain TCP.
+
+#[derive(Clone)]
+pub struct TlsContext {
+    connector: TlsConnector,
+    acceptor: TlsAcceptor
+}
+
+// A connection to another member, encrypted when TLS is configured. Clones share the same connection the same
+// way clones of a TcpStream do.
+#[derive(Clone)]
+pub struct ClusterStream {
+    tcp: TcpStream,
+    // Only ever locked for a single poll, never across an await
+    tls: Option<Arc<Mutex<TlsStream<TcpStream>>>>
+}
+
+pub fn load(config: &Config) -> BoxedErrorResult<Option<TlsContext>> {
+    let (ca_file, cert_file, key_file) = match (&config.tls_ca_file, &config.tls_cert_file, &config.tls_key_file) {
+        (Some(ca_file), Some(cert_file), Some(key_file)) => (ca_file, cert_file, key_file),
+        _ => return Ok(None)
+    };
+    let mut roots = RootCertStore::empty();
+    for ca_cert in read_certs(ca_file)? {
+        roots.add(ca_cert).map_err(|e| format!("Invalid CA certificate in {}: {}", ca_file, e))?;
+    }
+    let roots = Arc::new(roots);
+    let certs = read_certs(cert_file)?;
+    let key = read_key(key_file)?;
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let client_verifier = WebPkiClientVerifier::builder_with_provider(roots.clone(), provider.clone()).build()?;
+    let server_config = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?
+        .with_client_cert_verifier(client_verifier)
+        .with_single_cert(certs.clone(), key.clone_key())
+        .map_err(|e| format!("Invalid certificate or key in {} / {}: {}", cert_file, key_file, e))?;
+    let client_config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_client_auth_cert(certs, key)
+        .map_err(|e| format!("Invalid certificate or key in {} / {}: {}", cert_file, key_file, e))?;
+    Ok(Some(TlsContext {
+        connector: TlsConnector::from(Arc::new(client_config)),
+        acceptor: TlsAcceptor::from(Arc::new(server_config))
+    }))
+}
+
+fn read_certs(path: &String) -> BoxedErrorResult<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(File::open(path).map_err(|e| format!("Could not open {}: {}", path, e))?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(format!("No certificates found in {}", path).into())
+    }
+    Ok(certs)
+}
+
+fn read_key(path: &String) -> BoxedErrorResult<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(File::open(path).map_err(|e| format!("Could not open {}: {}", path, e))?);
+    Ok(rustls_pemfile::private_key(&mut reader)?.ok_or(format!("No private key found in {}", path))?)
+}
+
+pub async fn connect(addr: &String) -> BoxedErrorResult<ClusterStream> {
+    let tcp = TcpStream::connect(addr).await?;
+    let connector = globals::TLS.read().as_ref().map(|context| context.connector.clone());
+    match connector {
+        Some(connector) => {
+            // Members are reached by IP, so that is what their certificates have to be issued for
+            let server_name = ServerName::IpAddress(tcp.peer_addr()?.ip().into());
+            let tls = connector.connect(server_name, tcp.clone()).await
+                .map_err(|e| format!("TLS handshake with {} failed: {}", addr, e))?;
+            Ok(ClusterStream::new(tcp, Some(tls.into())))
+        },
+        None => Ok(ClusterStream::new(tcp, None))
+    }
+}
+
+pub async fn accept(tcp: TcpStream) -> BoxedErrorResult<ClusterStream> {
+    let acceptor = globals::TLS.read().as_ref().map(|context| context.acceptor.clone());
+    match acceptor {
+        Some(acceptor) => {
+            let peer_addr = tcp.peer_addr()?;
+            let tls = acceptor.accept(tcp.clone()).await
+                .map_err(|e| format!("TLS handshake with {} failed: {}", peer_addr, e))?;
+            Ok(ClusterStream::new(tcp, Some(tls.into())))
+        },
+        None => Ok(ClusterStream::new(tcp, None))
+    }
+}
+
+impl ClusterStream {
+    fn new(tcp: TcpStream, tls: Option<TlsStream<TcpStream>>) -> Self {
+        ClusterStream {
+            tcp,
+            tls: tls.map(|tls| Arc::new(Mutex::new(tls)))
+        }
+    }
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.tcp.peer_addr()
+    }
+    // TLS holds on to what is written until it is flushed, so every frame is flushed before waiting on a reply
+    pub async fn write_frame(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.write_all(buf).await?;
+        self.flush().await
+    }
+}
+
+impl fmt::Debug for ClusterStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClusterStream")
+         .field("peer_addr", &self.tcp.peer_addr())
+         .field("tls", &self.tls.is_some())
+         .finish()
+    }
+}
+
+impl Read for ClusterStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        match &this.tls {
+            Some(tls) => Pin::new(&mut *tls.lock().unwrap()).poll_read(cx, buf),
+            None => Pin::new(&mut this.tcp).poll_read(cx, buf)
+        }
+    }
+}
+
+impl Write for ClusterStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        match &this.tls {
+            Some(tls) => Pin::new(&mut *tls.lock().unwrap()).poll_write(cx, buf),
+            None => Pin::new(&mut this.tcp).poll_write(cx, buf)
+        }
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match &this.tls {
+            Some(tls) => Pin::new(&mut *tls.lock().unwrap()).poll_flush(cx),
+            None => Pin::new(&mut this.tcp).poll_flush(cx)
+        }
+    }
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match &this.tls {
+            Some(tls) => Pin::new(&mut *tls.lock().unwrap()).poll_close(cx),
+            None => Pin::new(&mut this.tcp).poll_close(cx)
+        }
+    }
+}